@@ -1,5 +1,7 @@
 use clap::{App, Arg};
 use std::env;
+use std::fmt::Display;
+use std::str::FromStr;
 
 use crate::types;
 
@@ -9,6 +11,44 @@ const VERSION: &str = env!("CARGO_PKG_VERSION");
 const DESCRIPTION: &str = env!("CARGO_PKG_DESCRIPTION");
 const AUTHORS: &str = env!("CARGO_PKG_AUTHORS");
 
+// Penalties are u8s in wflambda. Matches cost nothing so a mismatch or a gap
+// extension that costs nothing either would make the alignment meaningless.
+fn penalty(min: u8) -> impl Fn(String) -> Result<(), String> {
+    move |v: String| match v.parse::<u8>() {
+        Ok(p) if p >= min => Ok(()),
+        _ => Err(format!(
+            "expected a whole number between {} and {}",
+            min,
+            u8::MAX
+        )),
+    }
+}
+
+fn whole_number<T: FromStr + Display + 'static>(max: T) -> impl Fn(String) -> Result<(), String> {
+    move |v: String| match v.parse::<T>() {
+        Ok(_) => Ok(()),
+        Err(_) => Err(format!("expected a whole number between 0 and {}", max)),
+    }
+}
+
+// segment lengths and thread counts, neither of which can be 0
+fn positive_number(v: String) -> Result<(), String> {
+    match v.parse::<usize>() {
+        Ok(n) if n >= 1 => Ok(()),
+        _ => Err(format!(
+            "expected a whole number between 1 and {}",
+            usize::MAX
+        )),
+    }
+}
+
+fn fraction(v: String) -> Result<(), String> {
+    match v.parse::<f64>() {
+        Ok(f) if (0.0..=1.0).contains(&f) => Ok(()),
+        _ => Err(String::from("expected a number between 0 and 1")),
+    }
+}
+
 pub fn start() -> types::AppConfig {
     let matches = App::new(NAME)
         .version(VERSION)
@@ -18,50 +58,174 @@ pub fn start() -> types::AppConfig {
             Arg::with_name("input_paf")
                 .required(true)
                 .takes_value(true)
-                .help("Path to input PAF file"),
+                .help("Path to input PAF file, plain text or gzip/BGZF compressed. Use - for stdin"),
+        )
+        .arg(
+            Arg::with_name("output")
+                .short("o")
+                .long("output")
+                .value_name("FILE")
+                .takes_value(true)
+                .help("Path to write the filtered PAF to [default: stdout]"),
+        )
+        .arg(
+            Arg::with_name("alignment_output")
+                .long("alignment-output")
+                .value_name("FILE")
+                .takes_value(true)
+                .help("Path to write the global alignment of each pair to as PAF with a cg:Z: CIGAR"),
+        )
+        .arg(
+            Arg::with_name("support_output")
+                .long("support-output")
+                .value_name("FILE")
+                .takes_value(true)
+                .help("Path to write how much of each indexed record the global alignment runs along to"),
+        )
+        .arg(
+            Arg::with_name("report")
+                .short("r")
+                .long("report")
+                .value_name("FILE")
+                .takes_value(true)
+                .help("Path to write a report of records that need a closer look to"),
         )
         .arg(
             Arg::with_name("mashmap_file")
                 .short("m")
                 .long("mashmap_file")
                 .value_name("FILE")
-                .help("Path to output file from mashmap")
+                .help("Path to mappings from MashMap or wfmash (PAF), plain text or gzip/BGZF compressed")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("min_mapping_identity")
+                .long("min-mapping-identity")
+                .value_name("FLOAT")
+                .default_value("0")
+                .takes_value(true)
+                .validator(fraction)
+                .help("Ignore MashMap or wfmash mappings with a lower estimated identity"),
+        )
+        .arg(
+            Arg::with_name("fasta")
+                .short("f")
+                .long("fasta")
+                .value_name("FILE")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .help("Query and target sequences to tell matches from mismatches in M operations and for --match-mode. Plain text FASTA, indexed with FILE.fai if it exists. Can be given more than once"),
+        )
+        .arg(
+            Arg::with_name("match_mode")
+                .long("match-mode")
+                .value_name("MODE")
+                .default_value("index")
+                .takes_value(true)
+                .possible_values(&["index", "sequence", "both"])
+                .requires_if("sequence", "fasta")
+                .requires_if("both", "fasta")
+                .help("What makes a cell a match when aligning: lying in a match run of a PAF record (index), agreeing bases in the --fasta sequences (sequence) or both"),
+        )
         .arg(
             Arg::with_name("segment_length")
                 .short("s")
                 .long("segment-length")
                 .multiple(false)
                 .default_value("10")
+                .validator(positive_number)
                 .help("Segment length for aligning"),
         )
+        .arg(
+            Arg::with_name("overlap")
+                .long("overlap")
+                .value_name("FLOAT|INT")
+                .default_value("0.5")
+                .takes_value(true)
+                .validator(|v| v.parse::<types::Overlap>().map(|_| ()))
+                .help("Overlap of consecutive segments as a fraction of the segment length e.g. 0.5 or in bases e.g. 5"),
+        )
         .arg(
             Arg::with_name("mismatch")
                 .short("x")
                 .long("mismatch")
                 .multiple(false)
-                .hidden(true)
+                .value_name("INT")
                 .default_value("1")
-                .help("Mismatch score"),
+                .validator(penalty(1))
+                .help("Mismatch penalty"),
         )
         .arg(
             Arg::with_name("gap_open")
-                .short("o")
-                .long("gap-penalties")
+                .short("g")
+                .long("gap-open")
                 .multiple(false)
-                .hidden(true)
+                .value_name("INT")
                 .default_value("1")
-                .help("Gap opening score"),
+                .validator(penalty(0))
+                .help("Gap opening penalty"),
         )
         .arg(
             Arg::with_name("gap_extend")
                 .short("e")
                 .long("gap-extend")
                 .multiple(false)
-                .hidden(true)
+                .value_name("INT")
                 .default_value("1")
-                .help("Gap extension score"),
+                .validator(penalty(1))
+                .help("Gap extension penalty"),
+        )
+        .arg(
+            Arg::with_name("min_mapping_quality")
+                .long("min-mapq")
+                .value_name("INT")
+                .default_value("0")
+                .takes_value(true)
+                .validator(whole_number(u8::MAX))
+                .help("Drop records with a lower mapping quality before indexing"),
+        )
+        .arg(
+            Arg::with_name("min_block_length")
+                .long("min-block-length")
+                .value_name("INT")
+                .default_value("0")
+                .takes_value(true)
+                .validator(whole_number(u32::MAX))
+                .help("Drop records with a shorter alignment block before indexing"),
+        )
+        .arg(
+            Arg::with_name("min_identity")
+                .long("min-identity")
+                .value_name("FLOAT")
+                .default_value("0")
+                .takes_value(true)
+                .validator(fraction)
+                .help("Drop records with a lower gap-compressed identity, from de:f or the CIGAR, before indexing"),
+        )
+        .arg(
+            Arg::with_name("primary_only")
+                .long("primary-only")
+                .multiple(false)
+                .help("Drop records with a tp:A other than P before indexing [default: false]"),
+        )
+        .arg(
+            Arg::with_name("min_support_bases")
+                .long("min-support-bases")
+                .value_name("INT")
+                .default_value("1")
+                .takes_value(true)
+                .validator(whole_number(u32::MAX))
+                .help("Keep records with at least this many bases of their matches on the global alignment"),
+        )
+        .arg(
+            Arg::with_name("min_support_fraction")
+                .long("min-support-fraction")
+                .value_name("FLOAT")
+                .default_value("0")
+                .takes_value(true)
+                .validator(fraction)
+                .help("Keep records with at least this fraction of their matches on the global alignment"),
         )
         .arg(
             Arg::with_name("thread_count")
@@ -69,6 +233,7 @@ pub fn start() -> types::AppConfig {
                 .long("thread-count")
                 .default_value("8")
                 .takes_value(true)
+                .validator(positive_number)
                 .help("Number of threads to use"),
         )
         .arg(
@@ -78,6 +243,31 @@ pub fn start() -> types::AppConfig {
                 .multiple(false)
                 .help("To apply adaptive wavefront alignment [default: false]"),
         )
+        .arg(
+            Arg::with_name("bgzip")
+                .short("z")
+                .long("bgzip")
+                .multiple(false)
+                .help("Compress the output PAF with BGZF [default: false]"),
+        )
+        .arg(
+            Arg::with_name("trim")
+                .long("trim")
+                .multiple(false)
+                .help("Clip kept records to the span the global alignment supports, rewriting coordinates, cg, NM and match counts [default: false]"),
+        )
+        .arg(
+            Arg::with_name("approximate")
+                .long("approximate")
+                .multiple(false)
+                .help("Index records without a cg or cs field as one match block [default: false]"),
+        )
+        .arg(
+            Arg::with_name("skip_invalid")
+                .long("skip-invalid")
+                .multiple(false)
+                .help("Log and drop invalid records instead of aborting [default: false]"),
+        )
         .arg(
             Arg::with_name("v")
                 .short("v")
@@ -89,17 +279,44 @@ pub fn start() -> types::AppConfig {
     // Gets a value for config if supplied by user, or defaults to "default.conf"
     let paf_file_path: &str = matches.value_of("input_paf").unwrap();
     let mashmap_file_path: Option<&str> = matches.value_of("mashmap_file");
+    let min_mapping_identity: f64 = matches
+        .value_of("min_mapping_identity")
+        .unwrap()
+        .parse::<f64>()
+        .unwrap();
+    let fasta_file_paths: Vec<&str> = matches
+        .values_of("fasta")
+        .map_or(Vec::new(), |v| v.collect());
+    let match_mode: types::MatchMode = matches.value_of("match_mode").unwrap().parse().unwrap();
+    let output_file_path: Option<&str> = matches.value_of("output");
+    let alignment_file_path: Option<&str> = matches.value_of("alignment_output");
+    let support_file_path: Option<&str> = matches.value_of("support_output");
+    let report_file_path: Option<&str> = matches.value_of("report");
     let segment_length: usize = matches
         .value_of("segment_length")
         .unwrap()
         .parse::<usize>()
         .unwrap();
+    let overlap: types::Overlap = matches.value_of("overlap").unwrap().parse().unwrap();
+    if let types::Overlap::Bases(b) = overlap {
+        if b >= segment_length {
+            clap::Error::with_description(
+                "The overlap in bases has to be less than the segment length",
+                clap::ErrorKind::ValueValidation,
+            )
+            .exit();
+        }
+    }
     let thread_count: usize = matches
         .value_of("thread_count")
         .unwrap()
         .parse::<usize>()
         .unwrap();
     let adapt: bool = matches.is_present("adapt");
+    let bgzip: bool = matches.is_present("bgzip");
+    let trim: bool = matches.is_present("trim");
+    let approximate: bool = matches.is_present("approximate");
+    let skip_invalid: bool = matches.is_present("skip_invalid");
     let verbosity_level: u8 = matches.occurrences_of("v") as u8;
     let mismatch = matches.value_of("mismatch").unwrap().parse::<u8>().unwrap();
     let gap_extend = matches
@@ -115,13 +332,60 @@ pub fn start() -> types::AppConfig {
         gap_extend,
     };
 
-    types::AppConfig::new(
-        paf_file_path,
-        mashmap_file_path,
+    let prefilter = types::Prefilter {
+        min_mapping_quality: matches
+            .value_of("min_mapping_quality")
+            .unwrap()
+            .parse::<u8>()
+            .unwrap(),
+        min_block_length: matches
+            .value_of("min_block_length")
+            .unwrap()
+            .parse::<u32>()
+            .unwrap(),
+        min_identity: matches
+            .value_of("min_identity")
+            .unwrap()
+            .parse::<f64>()
+            .unwrap(),
+        primary_only: matches.is_present("primary_only"),
+    };
+
+    let min_support = types::MinSupport {
+        bases: matches
+            .value_of("min_support_bases")
+            .unwrap()
+            .parse::<u32>()
+            .unwrap(),
+        fraction: matches
+            .value_of("min_support_fraction")
+            .unwrap()
+            .parse::<f64>()
+            .unwrap(),
+    };
+
+    types::AppConfig {
+        input_paf: String::from(paf_file_path),
+        mashmap_filepath: mashmap_file_path.map(String::from),
+        min_mapping_identity,
+        fasta: fasta_file_paths.into_iter().map(String::from).collect(),
+        match_mode,
+        output: output_file_path.map(String::from),
+        alignment_output: alignment_file_path.map(String::from),
+        support_output: support_file_path.map(String::from),
+        report: report_file_path.map(String::from),
         segment_length,
+        overlap,
         thread_count,
-        Some(penalties),
+        penalties,
+        prefilter,
+        min_support,
         adapt,
+        bgzip,
+        trim,
+        approximate,
+        skip_invalid,
         verbosity_level,
-    )
+        ..Default::default()
+    }
 }
@@ -0,0 +1,172 @@
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+
+// What went wrong in a single field of a record.
+// Parsers don't know which file or line they are on, see Error::parse.
+#[derive(Debug, PartialEq)]
+pub struct FieldError {
+    pub field: String,
+    pub message: String,
+}
+
+impl FieldError {
+    pub fn new(field: &str, message: &str) -> Self {
+        FieldError {
+            field: String::from(field),
+            message: String::from(message),
+        }
+    }
+}
+
+impl fmt::Display for FieldError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid {}: {}", self.field, self.message)
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Io {
+        file: String,
+        source: io::Error,
+    },
+    Parse {
+        file: String,
+        line: usize, // 1-based line number
+        field: String,
+        message: String,
+    },
+    Record {
+        file: String,
+        record: usize, // numbered from 0 ignoring empty lines as in the report
+        field: String,
+        message: String,
+    },
+    Sequence {
+        name: String,
+        message: String,
+    },
+}
+
+impl Error {
+    pub fn io(file: &str, source: io::Error) -> Self {
+        Error::Io {
+            file: String::from(file),
+            source,
+        }
+    }
+
+    pub fn sequence(name: &str, message: &str) -> Self {
+        Error::Sequence {
+            name: String::from(name),
+            message: String::from(message),
+        }
+    }
+
+    // For records that are looked at again once the file has been parsed
+    pub fn record(file: &str, record: usize, e: FieldError) -> Self {
+        Error::Record {
+            file: String::from(file),
+            record,
+            field: e.field,
+            message: e.message,
+        }
+    }
+
+    pub fn parse(file: &str, line: usize, e: FieldError) -> Self {
+        Error::Parse {
+            file: String::from(file),
+            line,
+            field: e.field,
+            message: e.message,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io { file, source } => write!(f, "{}: {}", file, source),
+            Error::Parse {
+                file,
+                line,
+                field,
+                message,
+            } => write!(f, "{}:{}: invalid {}: {}", file, line, field, message),
+            Error::Record {
+                file,
+                record,
+                field,
+                message,
+            } => write!(
+                f,
+                "{}: record {}: invalid {}: {}",
+                file, record, field, message
+            ),
+            Error::Sequence { name, message } => write!(f, "sequence {}: {}", name, message),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+// Parse column index (0-based) of a tab separated record
+pub fn parse_column<T: FromStr>(
+    columns: &[&str],
+    index: usize,
+    name: &str,
+) -> Result<T, FieldError> {
+    let column = columns
+        .get(index)
+        .ok_or_else(|| FieldError::new(name, "missing column"))?;
+
+    T::from_str(column).map_err(|_| FieldError::new(name, &format!("could not parse {}", column)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_column() {
+        let columns: Vec<&str> = vec!["qry", "100", "x"];
+
+        assert_eq!(parse_column::<u32>(&columns, 1, "query length"), Ok(100));
+        assert_eq!(
+            parse_column::<u32>(&columns, 2, "query start"),
+            Err(FieldError::new("query start", "could not parse x"))
+        );
+        assert_eq!(
+            parse_column::<u32>(&columns, 3, "query end"),
+            Err(FieldError::new("query end", "missing column"))
+        );
+    }
+
+    #[test]
+    fn test_display() {
+        let e = Error::parse("x.paf", 3, FieldError::new("cg", "unexpected char Q"));
+        assert_eq!(format!("{}", e), "x.paf:3: invalid cg: unexpected char Q");
+
+        let e = Error::record("x.paf", 2, FieldError::new("cg", "unexpected char Q"));
+        assert_eq!(
+            format!("{}", e),
+            "x.paf: record 2: invalid cg: unexpected char Q"
+        );
+
+        let e = Error::sequence("chr1", "is in none of the FASTA files");
+        assert_eq!(
+            format!("{}", e),
+            "sequence chr1: is in none of the FASTA files"
+        );
+    }
+}
@@ -0,0 +1,359 @@
+/*
+Random access to sequences in plain text FASTA files through a samtools faidx
+style .fai index. The index is read from FILE.fai next to the FASTA or, if there
+is none, built by reading through the FASTA once.
+
+|Col|Type   |Description                               |
+|--:|:-----:|:-----------------------------------------|
+|1  |string |Sequence name                             |
+|2  |int    |Sequence length                           |
+|3  |int    |Offset of the first base in the file      |
+|4  |int    |Bases per line                            |
+|5  |int    |Bytes per line including the newline      |
+*/
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, BufRead};
+use std::path::Path;
+
+use crate::error::{parse_column, Error, FieldError, Result};
+use crate::io as wio;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FaiEntry {
+    pub length: u64,
+    pub offset: u64,
+    pub line_bases: u64,
+    pub line_width: u64,
+}
+
+impl FaiEntry {
+    // the offset in the file of the base at position
+    fn position(&self, position: u64) -> u64 {
+        self.offset + (position / self.line_bases) * self.line_width + position % self.line_bases
+    }
+}
+
+pub type Fai = HashMap<String, FaiEntry>;
+
+fn parse_fai_line(line: &str) -> Result<(String, FaiEntry), FieldError> {
+    let it: Vec<&str> = line.split('\t').collect();
+    let entry = FaiEntry {
+        length: parse_column(&it, 1, "sequence length")?,
+        offset: parse_column(&it, 2, "offset")?,
+        line_bases: parse_column(&it, 3, "line bases")?,
+        line_width: parse_column(&it, 4, "line width")?,
+    };
+    // FaiEntry::position divides by it
+    if entry.line_bases == 0 && entry.length > 0 {
+        return Err(FieldError::new("line bases", "0 for a sequence with bases"));
+    }
+
+    Ok((parse_column(&it, 0, "sequence name")?, entry))
+}
+
+pub fn read_fai<R: BufRead>(reader: R, file_name: &str) -> Result<Fai> {
+    let mut fai = Fai::new();
+
+    for (index, line) in reader.lines().enumerate() {
+        let line = line.map_err(|e| Error::io(file_name, e))?;
+        if line.is_empty() {
+            continue;
+        }
+
+        let (name, entry) =
+            parse_fai_line(&line[..]).map_err(|e| Error::parse(file_name, index + 1, e))?;
+        fai.insert(name, entry);
+    }
+
+    Ok(fai)
+}
+
+// Build the index of a FASTA the way samtools faidx does. Every line of a
+// sequence but the last has to be of the same length.
+pub fn build_fai<R: BufRead>(mut reader: R, file_name: &str) -> Result<Fai> {
+    let mut fai = Fai::new();
+    let mut current: Option<(String, FaiEntry)> = None;
+    // set once a sequence has a line shorter than the ones before it
+    let mut short_line = false;
+
+    let mut line: Vec<u8> = Vec::new();
+    let mut offset: u64 = 0;
+    let mut line_number: usize = 0;
+
+    loop {
+        line.clear();
+        let bytes_read = reader
+            .read_until(b'\n', &mut line)
+            .map_err(|e| Error::io(file_name, e))? as u64;
+        if bytes_read == 0 {
+            break;
+        }
+        offset += bytes_read;
+        line_number += 1;
+
+        if line.starts_with(b">") {
+            if let Some((name, entry)) = current.take() {
+                fai.insert(name, entry);
+            }
+            let header = String::from_utf8_lossy(&line[1..]);
+            let name = header.split_whitespace().next().unwrap_or("");
+            let entry = FaiEntry {
+                length: 0,
+                offset,
+                line_bases: 0,
+                line_width: 0,
+            };
+            current = Some((String::from(name), entry));
+            short_line = false;
+            continue;
+        }
+
+        let bases = line
+            .iter()
+            .take_while(|b| **b != b'\n' && **b != b'\r')
+            .count() as u64;
+        if let Some((_, entry)) = current.as_mut() {
+            if entry.line_bases == 0 {
+                entry.line_bases = bases;
+                entry.line_width = bytes_read;
+            } else if short_line || bases > entry.line_bases {
+                let e = FieldError::new("sequence", "lines of different length");
+                return Err(Error::parse(file_name, line_number, e));
+            }
+            short_line = short_line || bases < entry.line_bases;
+            entry.length += bases;
+        }
+    }
+
+    if let Some((name, entry)) = current.take() {
+        fai.insert(name, entry);
+    }
+
+    Ok(fai)
+}
+
+// Fill buf from offset in file without moving the file position so threads
+// can read from the same file at once
+#[cfg(unix)]
+fn read_exact_at(file: &fs::File, buf: &mut [u8], offset: u64) -> io::Result<()> {
+    use std::os::unix::fs::FileExt;
+
+    file.read_exact_at(buf, offset)
+}
+
+#[cfg(windows)]
+fn read_exact_at(file: &fs::File, mut buf: &mut [u8], mut offset: u64) -> io::Result<()> {
+    use std::os::windows::fs::FileExt;
+
+    while !buf.is_empty() {
+        match file.seek_read(buf, offset) {
+            Ok(0) => return Err(io::Error::from(io::ErrorKind::UnexpectedEof)),
+            Ok(n) => {
+                buf = &mut buf[n..];
+                offset += n as u64;
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(())
+}
+
+// A FASTA we can fetch any part of any sequence from
+pub struct IndexedFasta {
+    file_name: String,
+    fai: Fai,
+    file: fs::File,
+}
+
+impl IndexedFasta {
+    pub fn from_file(file_name: &str) -> Result<Self> {
+        if wio::is_compressed(file_name)? {
+            let e = io::Error::new(
+                io::ErrorKind::InvalidData,
+                "compressed FASTA is not supported",
+            );
+            return Err(Error::io(file_name, e));
+        }
+
+        let fai_name = format!("{}.fai", file_name);
+        let fai = if Path::new(&fai_name).exists() {
+            read_fai(wio::open_file(&fai_name)?, &fai_name)?
+        } else {
+            build_fai(wio::open_file(file_name)?, file_name)?
+        };
+        let file = fs::File::open(file_name).map_err(|e| Error::io(file_name, e))?;
+
+        Ok(IndexedFasta {
+            file_name: String::from(file_name),
+            fai,
+            file,
+        })
+    }
+
+    // The bases start..stop of sequence name in upper case.
+    // None if there is no sequence called name.
+    pub fn fetch(&self, name: &str, start: u64, stop: u64) -> Option<io::Result<Vec<u8>>> {
+        let entry = self.fai.get(name)?;
+        if start > stop || stop > entry.length {
+            let e = io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{}:{}-{} is beyond the end of {}", name, start, stop, name),
+            );
+            return Some(Err(e));
+        }
+        if start == stop {
+            return Some(Ok(Vec::new()));
+        }
+
+        let begin = entry.position(start);
+        let end = entry.position(stop - 1) + 1;
+        let mut bytes: Vec<u8> = vec![0; (end - begin) as usize];
+        if let Err(e) = read_exact_at(&self.file, &mut bytes, begin) {
+            return Some(Err(e));
+        }
+
+        bytes.retain(|b| *b != b'\n' && *b != b'\r');
+        bytes.make_ascii_uppercase();
+        Some(Ok(bytes))
+    }
+}
+
+// Sequences looked up by name across several FASTA files
+#[derive(Default)]
+pub struct Sequences {
+    files: Vec<IndexedFasta>,
+}
+
+impl Sequences {
+    pub fn from_files(file_names: &[String]) -> Result<Self> {
+        let files = file_names
+            .iter()
+            .map(|f| IndexedFasta::from_file(&f[..]))
+            .collect::<Result<Vec<IndexedFasta>>>()?;
+
+        Ok(Sequences { files })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty()
+    }
+
+    // The bases start..stop of sequence name
+    pub fn get(&self, name: &str, start: u64, stop: u64) -> Result<Vec<u8>> {
+        for fasta in &self.files {
+            if let Some(bases) = fasta.fetch(name, start, stop) {
+                return bases.map_err(|e| Error::io(&fasta.file_name[..], e));
+            }
+        }
+
+        Err(Error::sequence(name, "is in none of the FASTA files"))
+    }
+
+    // As get for a sequence named in field of a PAF record
+    pub fn fetch(
+        &self,
+        name: &str,
+        start: u64,
+        stop: u64,
+        field: &str,
+    ) -> Result<Vec<u8>, FieldError> {
+        self.get(name, start, stop).map_err(|e| match e {
+            Error::Sequence { message, .. } => {
+                FieldError::new(field, &format!("{} {}", name, message))
+            }
+            e => FieldError::new(field, &format!("{}", e)),
+        })
+    }
+}
+
+// Complement of a base, anything but ACGT becomes N
+fn complement(base: u8) -> u8 {
+    match base {
+        b'A' => b'T',
+        b'C' => b'G',
+        b'G' => b'C',
+        b'T' => b'A',
+        _ => b'N',
+    }
+}
+
+pub fn reverse_complement(sequence: &mut [u8]) {
+    sequence.reverse();
+    sequence.iter_mut().for_each(|b| *b = complement(*b));
+}
+
+// A FASTA with contents for tests, written to a temporary file that is removed
+// again once it is open
+#[cfg(test)]
+fn test_fasta(contents: &str) -> IndexedFasta {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    // tests run in parallel so every file gets a name of its own
+    static COUNT: AtomicUsize = AtomicUsize::new(0);
+    let path = std::env::temp_dir().join(format!(
+        "wffilter-test-{}-{}.fa",
+        std::process::id(),
+        COUNT.fetch_add(1, Ordering::Relaxed)
+    ));
+
+    fs::write(&path, contents).unwrap();
+    let fasta = IndexedFasta::from_file(path.to_str().unwrap()).unwrap();
+    fs::remove_file(&path).unwrap();
+    fasta
+}
+
+#[cfg(test)]
+pub fn test_sequences(contents: &str) -> Sequences {
+    Sequences {
+        files: vec![test_fasta(contents)],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // two sequences with lines of 4 bases
+    static TEST_FASTA: &str = ">one first sequence\nACGT\nACGT\nAC\n>two\nggcc\nTT\n";
+
+    #[test]
+    fn test_build_fai() {
+        let fai = build_fai(TEST_FASTA.as_bytes(), "test.fa").unwrap();
+        let fai_string = "one\t10\t20\t4\t5\ntwo\t6\t38\t4\t5\n";
+
+        assert_eq!(fai, read_fai(fai_string.as_bytes(), "test.fa.fai").unwrap());
+
+        let ragged = ">one\nACGT\nAC\nACGT\n";
+        assert!(build_fai(ragged.as_bytes(), "test.fa").is_err());
+
+        let e = read_fai("one\t10\t20\t0\t5\n".as_bytes(), "test.fa.fai").unwrap_err();
+        assert_eq!(
+            format!("{}", e),
+            "test.fa.fai:1: invalid line bases: 0 for a sequence with bases"
+        );
+        // an empty sequence has no lines
+        assert!(read_fai("empty\t0\t20\t0\t0\n".as_bytes(), "test.fa.fai").is_ok());
+    }
+
+    #[test]
+    fn test_fetch() {
+        let fasta = test_fasta(TEST_FASTA);
+
+        assert_eq!(fasta.fai.get("one").map(|e| e.length), Some(10));
+        assert_eq!(fasta.fetch("one", 2, 9).unwrap().unwrap(), b"GTACGTA");
+        assert_eq!(fasta.fetch("two", 0, 6).unwrap().unwrap(), b"GGCCTT");
+        assert!(fasta.fetch("two", 4, 7).unwrap().is_err());
+        assert!(fasta.fetch("three", 0, 1).is_none());
+    }
+
+    #[test]
+    fn test_reverse_complement() {
+        let mut sequence = b"AACGTN".to_vec();
+        reverse_complement(&mut sequence);
+        assert_eq!(sequence, b"NACGTT");
+    }
+}
@@ -0,0 +1,216 @@
+/*
+The global alignment of a pair, stitched together from the traceback of its
+segments and written out as a PAF record with a cg:Z: CIGAR.
+
+Traceback runs are matches. Between two runs the path takes as many mismatches
+along the diagonal as it can and makes up the rest with an insertion or a
+deletion. The record spans the path from its first to its last match, query
+coordinates are turned back around for reverse strand pairs.
+
+|Col|Type   |Description                               |
+|--:|:-----:|:-----------------------------------------|
+|10 |int    |Number of = operations                    |
+|11 |int    |Sum of the lengths of all operations      |
+|12 |int    |Mapping quality, always 255 (missing)     |
+|13 |tag    |NM:i: number of X, I and D bases          |
+|14 |tag    |cg:Z: CIGAR of =, X, I and D operations   |
+*/
+
+use std::fs;
+use std::io::{self, BufWriter, Write};
+
+use super::types::Strand;
+use crate::error::{Error, Result};
+
+// A diagonal run of matches on the alignment path. Query positions are in the
+// coordinates of the pair index i.e. reverse complement ones on the reverse strand.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PathRun {
+    pub target_start: usize,
+    pub query_start: usize,
+    pub length: usize,
+}
+
+// The match runs of a global alignment ordered along both sequences
+#[derive(Debug, Default)]
+pub struct Path {
+    runs: Vec<PathRun>,
+}
+
+impl Path {
+    // where the path ends on the target and the query
+    fn end(&self) -> Option<(usize, usize)> {
+        self.runs
+            .last()
+            .map(|r| (r.target_start + r.length, r.query_start + r.length))
+    }
+
+    // Add the traceback runs of the next segment. Consecutive segments overlap
+    // so whatever the path already covers on either sequence is dropped.
+    pub fn extend(&mut self, mut runs: Vec<PathRun>) {
+        // the traceback needn't report runs in order
+        runs.sort_by_key(|r| (r.target_start, r.query_start));
+
+        for run in runs {
+            let clip = match self.end() {
+                Some((t, q)) => std::cmp::max(
+                    t.saturating_sub(run.target_start),
+                    q.saturating_sub(run.query_start),
+                ),
+                None => 0,
+            };
+            if clip >= run.length {
+                continue;
+            }
+
+            let run = PathRun {
+                target_start: run.target_start + clip,
+                query_start: run.query_start + clip,
+                length: run.length - clip,
+            };
+
+            // runs that touch on the same diagonal are one run
+            match self.runs.last_mut() {
+                Some(last)
+                    if last.target_start + last.length == run.target_start
+                        && last.query_start + last.length == run.query_start =>
+                {
+                    last.length += run.length
+                }
+                _ => self.runs.push(run),
+            }
+        }
+    }
+
+    // The path as a PAF record, None if it has no matches
+    pub fn to_paf(
+        &self,
+        (query_name, query_length): (&str, usize),
+        (target_name, target_length): (&str, usize),
+        strand: Strand,
+    ) -> Option<String> {
+        let first = self.runs.first()?;
+        let (target_stop, query_stop) = self.end()?;
+
+        let mut ops: Vec<(usize, char)> = Vec::new();
+        let mut push = |n: usize, op: char| {
+            if n == 0 {
+                return;
+            }
+            match ops.last_mut() {
+                Some((m, last)) if *last == op => *m += n,
+                _ => ops.push((n, op)),
+            }
+        };
+
+        let mut previous: Option<&PathRun> = None;
+        for run in &self.runs {
+            if let Some(p) = previous {
+                let target_gap = run.target_start - (p.target_start + p.length);
+                let query_gap = run.query_start - (p.query_start + p.length);
+                let mismatches = std::cmp::min(target_gap, query_gap);
+
+                push(mismatches, 'X');
+                push(query_gap - mismatches, 'I');
+                push(target_gap - mismatches, 'D');
+            }
+            push(run.length, '=');
+            previous = Some(run);
+        }
+
+        let count = |wanted: &[char]| -> usize {
+            ops.iter()
+                .filter(|(_, op)| wanted.contains(op))
+                .map(|(n, _)| n)
+                .sum()
+        };
+        let matches = count(&['=']);
+        let differences = count(&['X', 'I', 'D']);
+        let cigar: String = ops.iter().map(|(n, op)| format!("{}{}", n, op)).collect();
+
+        let (query_start, query_stop) = match strand {
+            Strand::Forward => (first.query_start, query_stop),
+            Strand::Reverse => (
+                query_length.saturating_sub(query_stop),
+                query_length.saturating_sub(first.query_start),
+            ),
+        };
+
+        Some(format!(
+            "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t255\tNM:i:{}\tcg:Z:{}",
+            query_name,
+            query_length,
+            query_start,
+            query_stop,
+            strand,
+            target_name,
+            target_length,
+            first.target_start,
+            target_stop,
+            matches,
+            matches + differences,
+            differences,
+            cigar
+        ))
+    }
+}
+
+pub fn write<W: Write>(records: &[String], writer: &mut W) -> io::Result<()> {
+    for record in records {
+        writeln!(writer, "{}", record)?;
+    }
+
+    writer.flush()
+}
+
+pub fn to_file(records: &[String], fp: &str) -> Result<()> {
+    fs::File::create(fp)
+        .and_then(|file| write(records, &mut BufWriter::new(file)))
+        .map_err(|e| Error::io(fp, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(target_start: usize, query_start: usize, length: usize) -> PathRun {
+        PathRun {
+            target_start,
+            query_start,
+            length,
+        }
+    }
+
+    #[test]
+    fn test_extend() {
+        let mut path = Path::default();
+        path.extend(vec![run(6, 6, 4), run(0, 0, 5)]);
+        // the next segment overlaps the last one
+        path.extend(vec![run(5, 5, 1), run(8, 8, 4), run(13, 15, 2)]);
+
+        assert_eq!(path.runs, vec![run(0, 0, 5), run(6, 6, 6), run(13, 15, 2)]);
+    }
+
+    #[test]
+    fn test_to_paf() {
+        let mut path = Path::default();
+        path.extend(vec![run(2, 0, 5), run(8, 6, 4), run(15, 10, 3)]);
+
+        assert_eq!(
+            path.to_paf(("qry", 20), ("tgt", 30), Strand::Forward)
+                .unwrap(),
+            "qry\t20\t0\t13\t+\ttgt\t30\t2\t18\t12\t16\t255\tNM:i:4\tcg:Z:5=1X4=3D3="
+        );
+
+        // query positions are in reverse complement coordinates
+        assert_eq!(
+            path.to_paf(("qry", 20), ("tgt", 30), Strand::Reverse)
+                .unwrap(),
+            "qry\t20\t7\t20\t-\ttgt\t30\t2\t18\t12\t16\t255\tNM:i:4\tcg:Z:5=1X4=3D3="
+        );
+
+        assert!(Path::default()
+            .to_paf(("qry", 20), ("tgt", 30), Strand::Forward)
+            .is_none());
+    }
+}
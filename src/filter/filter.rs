@@ -1,236 +1,439 @@
-use coitrees::{COITree, IntervalNode};
-use indicatif::{ParallelProgressIterator, ProgressBar, ProgressStyle, MultiProgress};
+use console::style;
+use indicatif::{ProgressBar, ProgressStyle};
 use rayon::prelude::*;
-use std::collections::HashSet;
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
 use std::collections::HashMap;
-use console::style;
+use std::collections::HashSet;
 
 use wflambda_rs as wflambda;
 
+use super::alignment::{Path, PathRun};
+use super::support::{self, Support};
 use super::types::*;
-use crate::paf;
-use crate::types::AppConfig;
+use crate::error::Result;
+use crate::fasta::{self, Sequences};
 use crate::mashmap;
+use crate::paf;
+use crate::types::{AppConfig, MatchMode};
+
+// What filtering keeps: the lines of the PAF records to output and the global
+// alignments as PAF records
+#[derive(Debug, Default)]
+pub struct Filtered {
+    pub lines: Vec<usize>,
+    pub alignments: Vec<String>,
+    // the target span of each kept line the global alignment supports
+    pub spans: HashMap<usize, (u32, u32)>,
+    pub support: BTreeMap<usize, Support>, // of every indexed record
+}
 
-pub fn generate_segments(tlen: usize, qlen: usize, config: &AppConfig) -> Vec<Segment> {
+// Tile the rectangle target_start..target_stop x query_start..query_stop with
+// square segments along its diagonal. The last segment reaches the far corner.
+pub fn generate_segments(
+    (target_start, target_stop): (usize, usize),
+    (query_start, query_stop): (usize, usize),
+    config: &AppConfig,
+) -> Vec<Segment> {
     let segment_length: usize = config.segment_length;
-    let step_size = (segment_length as f64 / 2_f64).floor() as usize;
+    let step_size = config.step_size();
+    let span = std::cmp::min(target_stop - target_start, query_stop - query_start);
 
     let mut segments: Vec<((usize, usize), (usize, usize))> = Vec::new();
 
     let mut start: usize = 0;
     let mut stop: usize = 0;
 
-    while stop < std::cmp::min(tlen, qlen) {
+    while stop < span {
         stop = start + segment_length;
-        if stop >= std::cmp::min(tlen, qlen) {
-            segments.push(((start, tlen), (start, qlen)));
+        if stop >= span {
+            segments.push((
+                (target_start + start, target_stop),
+                (query_start + start, query_stop),
+            ));
         } else {
-            segments.push(((start, stop), (start, stop)));
+            segments.push((
+                (target_start + start, target_start + stop),
+                (query_start + start, query_start + stop),
+            ));
         }
 
-        if config.step {
-            start += step_size;
-        } else {
-            start += segment_length;
-        }
+        start += step_size;
     }
 
     segments
 }
 
-fn run_aln(
-    segments: &Vec<Segment>,
-    index: &Index,
-    target_name: &str,
-    query_name: &str,
-    wflambda_config: &wflambda::Config,
-    matching_regions: &mut HashSet<QueryResult>,
-    bar: Option<&ProgressBar>,
-) {
-    let query_index: &COITree<PafMetadata, u32> = &index.query_index;
-    let target_index: &COITree<PafMetadata, u32> = &index.target_index;
-
-    for (_index, segment) in segments.iter().enumerate() {
-        let ((tstart, tstop), (qstart, qstop)) = *segment;
-
-        let mut match_matches: HashSet<QueryResult> = HashSet::new();
-        let mut traceback_matches: HashSet<QueryResult> = HashSet::new();
-
-        let mut match_lambda = |v: &mut usize, h: &mut usize| -> bool {
-            // We are matching segments that are the size of segment_length
-            // add v and h by qstart and tstart to make up for the offset created by the segment
-            // we are basically doing position in the segment + position of the segment
-            let v_start = (*v + qstart) as i32;
-            let h_start = (*h + tstart) as i32;
+// Pick a collinear chain of PAF records, longest first, and return the path
+// through their corners ordered along the target. A record only joins the chain
+// if it comes after the records before it and before those after it on both
+// sequences. Where chained records overlap the path skips the overlap.
+pub fn chain(blocks: &[Block]) -> Vec<(usize, usize)> {
+    let comes_before = |a: &Block, b: &Block| {
+        a.target_start <= b.target_start
+            && a.target_stop <= b.target_stop
+            && a.query_start <= b.query_start
+            && a.query_stop <= b.query_stop
+    };
 
-            let v_stop = (*v + qstop) as i32;
-            let h_stop = (*h + tstop) as i32;
+    // stable so that records of the same size are taken in PAF order
+    let mut by_length: Vec<&Block> = blocks.iter().collect();
+    by_length.sort_by_key(|b| {
+        Reverse((b.target_stop - b.target_start) + (b.query_stop - b.query_start))
+    });
+
+    let mut chained: BTreeMap<(u32, u32), &Block> = BTreeMap::new();
+    for block in by_length {
+        let key = (block.target_start, block.line_num);
+        let previous = chained.range(..key).next_back().map(|(_, b)| *b);
+        let next = chained.range(key..).next().map(|(_, b)| *b);
+
+        if previous.into_iter().all(|p| comes_before(p, block))
+            && next.into_iter().all(|n| comes_before(block, n))
+        {
+            chained.insert(key, block);
+        }
+    }
 
-            *v = v_stop as usize;
-            *h = h_stop as usize;
+    let mut path: Vec<(usize, usize)> = Vec::new();
+    for block in chained.values() {
+        let start = (block.target_start as usize, block.query_start as usize);
+        let start = match path.last() {
+            Some(&(t, q)) => (std::cmp::max(t, start.0), std::cmp::max(q, start.1)),
+            None => start,
+        };
+        path.push(start);
+        path.push((block.target_stop as usize, block.query_stop as usize));
+    }
 
-            let mut query_cache: HashSet<QueryResult> = HashSet::new();
-            let mut target_cache: HashSet<QueryResult> = HashSet::new();
+    path
+}
 
-            let handle_targets = |i: &IntervalNode<PafMetadata, u32>| {
-                if i.metadata.name != target_name {
-                    return;
-                }
+// Tile a path through the alignment matrix, such as one from chain, with
+// segments that follow it. Pieces of the path are measured along their longer
+// side and each segment covers segment_length of that. Segments that would be
+// empty on either sequence can't hold a match and are left out.
+pub fn generate_path_segments(path: &[(usize, usize)], config: &AppConfig) -> Vec<Segment> {
+    let segment_length: usize = config.segment_length;
+    let step_size = config.step_size();
+
+    // how far along the path each of its points is
+    let mut distances: Vec<usize> = Vec::with_capacity(path.len());
+    let mut total: usize = 0;
+    for (i, (t, q)) in path.iter().enumerate() {
+        if i > 0 {
+            let (previous_t, previous_q) = path[i - 1];
+            total += std::cmp::max(t - previous_t, q - previous_q);
+        }
+        distances.push(total);
+    }
 
-                let res = QueryResult {
-                    line: i.metadata.line_num,
+    // interpolate the point distance along the path
+    let point_at = |distance: usize| -> (usize, usize) {
+        let i = distances.partition_point(|d| *d <= distance);
+        if i == path.len() {
+            return path[path.len() - 1];
+        }
 
-                    sequence_start: i.first,
-                    sequence_stop: i.last,
+        let ((t0, q0), (t1, q1)) = (path[i - 1], path[i]);
+        let length = distances[i] - distances[i - 1];
+        let offset = distance - distances[i - 1];
+        (
+            t0 + (t1 - t0) * offset / length,
+            q0 + (q1 - q0) * offset / length,
+        )
+    };
 
-                    segment_qstart: qstart,
-                    segment_qstop: qstop,
-                    segment_tstart: tstart,
-                    segment_tstop: tstop,
-                };
+    let mut segments: Vec<Segment> = Vec::new();
 
-                target_cache.insert(res);
-            };
+    let mut start: usize = 0;
+    let mut stop: usize = 0;
 
-            let handle_queries = |i: &IntervalNode<PafMetadata, u32>| {
-                if i.metadata.name != query_name {
-                    return;
-                }
+    while stop < total {
+        stop = start + segment_length;
 
-                let res = QueryResult {
-                    line: i.metadata.line_num,
+        let (tstart, qstart) = point_at(start);
+        let (tstop, qstop) = if stop >= total {
+            path[path.len() - 1]
+        } else {
+            point_at(stop)
+        };
 
-                    sequence_start: i.first,
-                    sequence_stop: i.last,
+        if tstart < tstop && qstart < qstop {
+            segments.push(((tstart, tstop), (qstart, qstop)));
+        }
 
-                    segment_qstart: qstart,
-                    segment_qstop: qstop,
-                    segment_tstart: tstart,
-                    segment_tstop: tstop,
-                };
+        start += step_size;
+    }
 
-                query_cache.insert(res);
-            };
+    segments
+}
 
-            target_index.query(h_start, h_stop, handle_targets);
-            query_index.query(v_start, v_stop, handle_queries);
+// How run_aln aligns segments and tells matches from mismatches
+#[derive(Clone, Copy)]
+struct Aligner<'a> {
+    wflambda_config: &'a wflambda::Config,
+    mode: MatchMode,
+    sequences: &'a Sequences,
+    query_length: usize, // to turn reverse complement query positions around
+}
 
-            target_cache
-                .intersection(&query_cache)
-                .for_each(|matches: &QueryResult| {
-                    match_matches.insert(*matches);
-                    // println!("match\t{}\t{}\t{}\t{}\t{}\t{}", target_name, query_name, v_start, v_stop, h_start, h_stop);
-                });
+// The target and query bases of a segment. Query positions of reverse strand
+// pairs are in reverse complement coordinates so the query bases are too.
+fn segment_bases(
+    sequences: &Sequences,
+    (target_name, query_name): (&str, &str),
+    strand: Strand,
+    query_length: usize,
+    ((tstart, tstop), (qstart, qstop)): Segment,
+) -> Result<(Vec<u8>, Vec<u8>)> {
+    let target = sequences.get(target_name, tstart as u64, tstop as u64)?;
+    let query = match strand {
+        Strand::Forward => sequences.get(query_name, qstart as u64, qstop as u64)?,
+        Strand::Reverse => {
+            let start = query_length.saturating_sub(qstop) as u64;
+            let stop = query_length.saturating_sub(qstart) as u64;
+            let mut query = sequences.get(query_name, start, stop)?;
+            fasta::reverse_complement(&mut query);
+            query
+        }
+    };
 
-            target_cache.intersection(&query_cache).next().is_some()
-        };
+    Ok((target, query))
+}
 
-        let mut traceback_lambda =
-            |(q_start, q_stop): (i32, i32), (t_start, t_stop): (i32, i32)| {
-                let mut query_cache: HashSet<QueryResult> = HashSet::new();
-                let mut target_cache: HashSet<QueryResult> = HashSet::new();
+fn run_aln(
+    segments: &Vec<Segment>,
+    index: &Index,
+    target_name: &str,
+    query_name: &str,
+    strand: Strand,
+    aligner: Aligner,
+    matching_regions: &mut HashSet<QueryResult>,
+) -> Result<Path> {
+    let mut path = Path::default();
 
-                let handle_targets = |i: &IntervalNode<PafMetadata, u32>| {
-                    if i.metadata.name != target_name {
-                        return;
-                    }
+    let pair_index: &PairIndex = match index.get(query_name, target_name, strand) {
+        Some(p) => p,
+        // no matches to guide the alignment
+        _ => return Ok(path),
+    };
 
-                    let res = QueryResult {
-                        line: i.metadata.line_num,
+    for segment in segments.iter() {
+        let ((tstart, tstop), (qstart, qstop)) = *segment;
 
-                        sequence_start: i.first,
-                        sequence_stop: i.last,
+        let tlen = tstop - tstart;
+        let qlen = qstop - qstart;
 
-                        segment_qstart: qstart,
-                        segment_qstop: qstop,
-                        segment_tstart: tstart,
-                        segment_tstop: tstop,
-                    };
+        let bases = if aligner.mode.uses_sequence() {
+            let names = (target_name, query_name);
+            Some(segment_bases(
+                aligner.sequences,
+                names,
+                strand,
+                aligner.query_length,
+                *segment,
+            )?)
+        } else {
+            None
+        };
 
-                    target_cache.insert(res);
-                };
+        let mut match_lambda = |v: &mut usize, h: &mut usize| -> bool {
+            // We are matching segments that are the size of segment_length
+            // add v and h by qstart and tstart to make up for the offset created by the segment
+            // we are basically doing position in the segment + position of the segment
+            let v_global = (*v + qstart) as u32;
+            let h_global = (*h + tstart) as u32;
 
-                let handle_queries = |i: &IntervalNode<PafMetadata, u32>| {
-                    if i.metadata.name != query_name {
-                        return;
-                    }
+            // skip as far along the diagonal as we can without leaving the segment
+            let mut skip = std::cmp::min(tlen - *h, qlen - *v);
 
-                    let res = QueryResult {
-                        line: i.metadata.line_num,
+            if aligner.mode.uses_index() {
+                // the furthest a match run containing (v, h) goes along the diagonal
+                let mut run_stop: Option<u32> = None;
+                pair_index.query_cell(v_global, h_global, |m: &PafMetadata| {
+                    run_stop = std::cmp::max(run_stop, Some(m.target_stop));
+                });
 
-                        sequence_start: i.first,
-                        sequence_stop: i.last,
+                match run_stop {
+                    Some(run_stop) => skip = std::cmp::min(skip, (run_stop - h_global) as usize),
+                    None => return false,
+                }
+            }
+
+            if let Some((target, query)) = &bases {
+                // as far as the bases agree, N agrees with nothing
+                skip = target[*h..*h + skip]
+                    .iter()
+                    .zip(&query[*v..*v + skip])
+                    .take_while(|(t, q)| t == q && **t != b'N')
+                    .count();
+            }
+
+            // at the edge of the segment or at a run that ends here
+            if skip == 0 {
+                return false;
+            }
+
+            *v += skip;
+            *h += skip;
+
+            true
+        };
 
-                        segment_qstart: qstart,
-                        segment_qstop: qstop,
-                        segment_tstart: tstart,
-                        segment_tstop: tstop,
-                    };
+        let mut segment_runs: Vec<PathRun> = Vec::new();
 
-                    query_cache.insert(res);
-                };
+        let mut traceback_lambda =
+            |(q_start, q_stop): (i32, i32), (t_start, t_stop): (i32, i32)| {
+                // only diagonal runs of the path can overlap match runs
+                let length = std::cmp::min(q_stop - q_start, t_stop - t_start);
+                if length <= 0 {
+                    return;
+                }
 
-                target_index.query(t_start, t_stop, handle_targets);
-                query_index.query(q_start, q_stop, handle_queries);
+                let v_global = q_start as u32 + qstart as u32;
+                let h_global = t_start as u32 + tstart as u32;
 
-                target_cache.intersection(&query_cache).for_each(|match_| {
-                    // println!("traceback\t{}\t{}\t{}\t{}\t{}\t{}", target_name, query_name, q_start, q_stop, t_start, t_stop);
-                    traceback_matches.insert(*match_);
+                segment_runs.push(PathRun {
+                    target_start: h_global as usize,
+                    query_start: v_global as usize,
+                    length: length as usize,
                 });
-            };
 
-        let tlen = tstop - tstart;
-        let qlen = qstop - qstart;
+                pair_index.query_run(
+                    v_global,
+                    h_global,
+                    length as u32,
+                    |m: &PafMetadata, overlap_start: u32, overlap_stop: u32| {
+                        let res = QueryResult {
+                            line: m.line_num,
+
+                            sequence_start: overlap_start as i32,
+                            sequence_stop: overlap_stop as i32,
+
+                            segment_qstart: qstart,
+                            segment_qstop: qstop,
+                            segment_tstart: tstart,
+                            segment_tstop: tstop,
+                        };
+
+                        matching_regions.insert(res);
+                    },
+                );
+            };
 
         wflambda::wf_align(
             tlen,
             qlen,
-            &wflambda_config,
+            aligner.wflambda_config,
             &mut match_lambda,
             &mut traceback_lambda,
         );
 
-        traceback_matches
-            .intersection(&match_matches)
-            .for_each(|e| {
-                matching_regions.insert(*e);
-            });
+        path.extend(segment_runs);
+    }
+
+    Ok(path)
+}
 
-        match bar {
-            Some(progress) => progress.inc(1),
-            _ => (),
-        }
+// The query length comes from the PAF, the same as the index uses for reverse
+// complement coordinates
+fn aligner<'a>(
+    wflambda_config: &'a wflambda::Config,
+    paf: &paf::PAF,
+    query_name: &str,
+    sequences: &'a Sequences,
+    config: &AppConfig,
+) -> Aligner<'a> {
+    Aligner {
+        wflambda_config,
+        mode: config.match_mode,
+        sequences,
+        query_length: paf
+            .get_metadata()
+            .get(query_name)
+            .map_or(0, |m| m.length as usize),
     }
 }
 
+// The global alignment of a pair as a PAF record if --alignment-output asks for it
+fn alignment_record(
+    path: &Path,
+    paf: &paf::PAF,
+    (query_name, target_name): (&str, &str),
+    strand: Strand,
+    config: &AppConfig,
+) -> Option<String> {
+    config.alignment_output.as_ref()?;
 
-pub fn filter(index: &Index, paf: &paf::PAF, config: &AppConfig) -> Vec<usize> {
-    let verbosity = config.verbosity_level;
+    let length = |name: &str| {
+        paf.get_metadata()
+            .get(name)
+            .map_or(0, |m| m.length as usize)
+    };
+    path.to_paf(
+        (query_name, length(query_name)),
+        (target_name, length(target_name)),
+        strand,
+    )
+}
 
-    let alignment_pairs: HashSet<paf::AlignmentPair> = paf.get_unique_alignments();
-    let metadata = paf.get_metadata();
+// The target span from the first to the last position at which the traceback
+// runs along a match run of each line
+fn supported_spans(all_matching_regions: &[HashSet<QueryResult>]) -> HashMap<usize, (u32, u32)> {
+    let mut spans: HashMap<usize, (u32, u32)> = HashMap::new();
 
-    if verbosity > 1 {
-        eprintln!(
-            "[wffilter::filter::filter] aligning {} pairs",
-            alignment_pairs.len()
-        );
+    for region in all_matching_regions.iter().flatten() {
+        let (start, stop) = (region.sequence_start as u32, region.sequence_stop as u32);
+        let span = spans.entry(region.line as usize).or_insert((start, stop));
+        *span = (std::cmp::min(span.0, start), std::cmp::max(span.1, stop));
     }
 
-    let wflambda_config = wflambda::Config {
+    spans
+}
+
+// Pairs are aligned in parallel, sort their records for a stable output
+fn sort_alignments(alignments: Vec<Option<String>>) -> Vec<String> {
+    let mut alignments: Vec<String> = alignments.into_iter().flatten().collect();
+    alignments.sort();
+    alignments
+}
+
+// The same wflambda config is used for every segment of every pair
+fn wflambda_config(config: &AppConfig) -> wflambda::Config {
+    wflambda::Config {
         adapt: config.adapt,
-        segment_length: config.segment_length as u32, // TODO: remove
-        step_size: 500,                               // TODO: remove
+        segment_length: config.segment_length as u32,
+        step_size: config.step_size() as u32,
         thread_count: config.thread_count,
         verbosity: config.verbosity_level,
         penalties: wflambda::Penalties {
             mismatch: config.penalties.mismatch,
             matches: config.penalties.matches,
-            gap_open: config.penalties.matches,
+            gap_open: config.penalties.gap_open,
             gap_extend: config.penalties.gap_extend,
         },
-    };
+    }
+}
+
+pub fn filter(
+    index: &Index,
+    paf: &paf::PAF,
+    sequences: &Sequences,
+    config: &AppConfig,
+) -> Result<Filtered> {
+    let verbosity = config.verbosity_level;
+
+    let alignment_pairs: &HashSet<paf::AlignmentPair> = paf.get_unique_alignments();
+
+    if verbosity > 1 {
+        eprintln!(
+            "[wffilter::filter::filter] aligning {} pairs",
+            alignment_pairs.len()
+        );
+    }
+
+    let wflambda_config = wflambda_config(config);
 
     // Progress bar
     let progress_bar = ProgressBar::new(alignment_pairs.len() as u64);
@@ -241,37 +444,45 @@ pub fn filter(index: &Index, paf: &paf::PAF, config: &AppConfig) -> Vec<usize> {
     progress_bar.set_style(progress_style);
 
     // Filter all the alignments
-    let all_matching_regions: Vec<HashSet<QueryResult>> = alignment_pairs
+    let results: Vec<(HashSet<QueryResult>, Option<String>)> = alignment_pairs
         .par_iter()
         .map(|alignment_pair: &paf::AlignmentPair| {
             let target_name = &alignment_pair.target_name[..];
             let query_name = &alignment_pair.query_name[..];
 
-            let tlen = metadata.get(target_name).unwrap().length;
-            let qlen = metadata.get(query_name).unwrap().length;
+            // Reverse strand pairs are aligned with the query axis flipped i.e.
+            // in the reverse complement coordinates the query index uses for them
+            let strand = alignment_pair.strand;
 
-            let segments = generate_segments(tlen as usize, qlen as usize, config);
+            // follow the records of the pair rather than the main diagonal
+            let segments = match index.get(query_name, target_name, strand) {
+                Some(pair_index) => generate_path_segments(&chain(&pair_index.blocks), config),
+                None => Vec::new(),
+            };
 
             let x = format!("{}", segments.len());
             progress_bar.set_message(x);
 
             let mut matching_regions: HashSet<QueryResult> = HashSet::new();
 
-            run_aln(
+            let path = run_aln(
                 &segments,
                 index,
                 target_name,
                 query_name,
-                &wflambda_config,
+                strand,
+                aligner(&wflambda_config, paf, query_name, sequences, config),
                 &mut matching_regions,
-                None,
-            );
+            )?;
+            let alignment = alignment_record(&path, paf, (query_name, target_name), strand, config);
 
             progress_bar.inc(1);
 
-            matching_regions
+            Ok((matching_regions, alignment))
         })
-        .collect();
+        .collect::<Result<_>>()?;
+    let (all_matching_regions, alignments): (Vec<HashSet<QueryResult>>, Vec<Option<String>>) =
+        results.into_iter().unzip();
 
     // Extract the necessary lines
     if all_matching_regions.is_empty() {
@@ -281,43 +492,35 @@ pub fn filter(index: &Index, paf: &paf::PAF, config: &AppConfig) -> Vec<usize> {
             );
         }
 
-        return Vec::new();
+        return Ok(Filtered::default());
     }
 
-    let extract_lines = |query_results: &HashSet<QueryResult>| -> Vec<usize> {
-        query_results
-            .iter()
-            .map(|query_restult: &QueryResult| query_restult.line as usize)
-            .collect()
-    };
-
-    let mut lines: Vec<usize> = all_matching_regions
-        .iter()
-        .map(extract_lines)
-        .flatten()
-        .collect();
+    let support = support::record_support(index, &all_matching_regions);
 
-    lines.sort();
-    lines.dedup();
-
-    lines
+    Ok(Filtered {
+        lines: support::supported_lines(&support, &config.min_support),
+        alignments: sort_alignments(alignments),
+        spans: supported_spans(&all_matching_regions),
+        support,
+    })
 }
 
 pub fn filter_mashmap(
     mashmap_mappings: Option<&mashmap::MashMapOutput>,
     index: &Index,
     paf: &paf::PAF,
+    sequences: &Sequences,
     config: &AppConfig,
-) -> Vec<usize> {
+) -> Result<Filtered> {
     let verbosity = config.verbosity_level;
 
     let mashmap_mappings = match mashmap_mappings {
         Some(m) => m,
-        _ => return Vec::new(),
+        _ => return Ok(Filtered::default()),
     };
 
     let unique_mappings: HashMap<mashmap::AlignmentPair, HashSet<mashmap::AlignmentBounds>> =
-        mashmap_mappings.gen_unique_mappings();
+        mashmap_mappings.gen_unique_mappings(config.min_mapping_identity);
     let mappings_count = unique_mappings.len();
 
     if verbosity > 1 {
@@ -327,58 +530,57 @@ pub fn filter_mashmap(
         );
     }
 
-    let wflambda_config = wflambda::Config {
-        adapt: config.adapt,
-        segment_length: config.segment_length as u32, // TODO: remove
-        step_size: 500,                               // TODO: remove
-        thread_count: config.thread_count,
-        verbosity: config.verbosity_level,
-        penalties: wflambda::Penalties {
-            mismatch: config.penalties.mismatch,
-            matches: config.penalties.matches,
-            gap_open: config.penalties.matches,
-            gap_extend: config.penalties.gap_extend,
-        },
-    };
-
-    let mut all_matching_regions: Vec<HashSet<QueryResult>> = Vec::new();
+    let wflambda_config = wflambda_config(config);
 
-    for (alignment_pair, bounds) in unique_mappings {
+    // Align every bound of every pair independently
+    let bounds: Vec<(&mashmap::AlignmentPair, &mashmap::AlignmentBounds)> = unique_mappings
+        .iter()
+        .flat_map(|(alignment_pair, bounds)| {
+            bounds.iter().map(move |bound| (alignment_pair, bound))
+        })
+        .collect();
 
-        let target_name = &alignment_pair.target[..];
-        let query_name = &alignment_pair.query[..];
+    // Progress bar
+    let progress_bar = ProgressBar::new(bounds.len() as u64);
+    let template = "{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}]  {pos:>7}/{len:7}  {msg} segments ({eta_precise})";
+    let progress_style = ProgressStyle::default_bar()
+        .template(template)
+        .progress_chars("=> ");
+    progress_bar.set_style(progress_style);
 
-        if verbosity > 1 {
-            eprintln!("\t{} and {}", target_name, query_name);
-        }
+    let results: Vec<(HashSet<QueryResult>, Option<String>)> = bounds
+        .par_iter()
+        .map(|(alignment_pair, bound)| {
+            let target_name = &alignment_pair.target[..];
+            let query_name = &alignment_pair.query[..];
 
-        for bound in bounds {
-            let tlen = bound.target_length;
-            let qlen = bound.query_length;
+            // only align within the approximate homology block of the mapping
+            let segments = generate_segments(bound.target_range(), bound.query_range(), config);
 
-            let segments = generate_segments(tlen as usize, qlen as usize, config);
-            let progress_bar = ProgressBar::new(segments.len() as u64);
-            let template = "\t{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {pos:>7}/{len:7} ({eta_precise})";
-            let progress_style = ProgressStyle::default_bar()
-                .template(template)
-                .progress_chars("=> ");
-            progress_bar.set_style(progress_style);
+            let x = format!("{}", segments.len());
+            progress_bar.set_message(x);
 
             let mut matching_regions: HashSet<QueryResult> = HashSet::new();
 
-            run_aln(
+            let path = run_aln(
                 &segments,
                 index,
                 target_name,
                 query_name,
-                &wflambda_config,
+                bound.strand,
+                aligner(&wflambda_config, paf, query_name, sequences, config),
                 &mut matching_regions,
-                Some(&progress_bar),
-            );
+            )?;
+            let alignment =
+                alignment_record(&path, paf, (query_name, target_name), bound.strand, config);
 
-            all_matching_regions.push(matching_regions);
-        }
-    }
+            progress_bar.inc(1);
+
+            Ok((matching_regions, alignment))
+        })
+        .collect::<Result<_>>()?;
+    let (all_matching_regions, alignments): (Vec<HashSet<QueryResult>>, Vec<Option<String>>) =
+        results.into_iter().unzip();
 
     // Extract the necessary lines
     if all_matching_regions.is_empty() {
@@ -388,24 +590,126 @@ pub fn filter_mashmap(
             );
         }
 
-        return Vec::new();
+        return Ok(Filtered::default());
     }
 
-    let extract_lines = |query_results: &HashSet<QueryResult>| -> Vec<usize> {
-        query_results
-            .iter()
-            .map(|query_restult: &QueryResult| query_restult.line as usize)
-            .collect()
-    };
+    let support = support::record_support(index, &all_matching_regions);
 
-    let mut lines: Vec<usize> = all_matching_regions
-        .iter()
-        .map(extract_lines)
-        .flatten()
-        .collect();
+    Ok(Filtered {
+        lines: support::supported_lines(&support, &config.min_support),
+        alignments: sort_alignments(alignments),
+        spans: supported_spans(&all_matching_regions),
+        support,
+    })
+}
 
-    lines.sort();
-    lines.dedup();
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{test_config, Overlap};
 
-    lines
+    #[test]
+    fn test_generate_segments() {
+        let config = test_config();
+
+        assert_eq!(
+            generate_segments((0, 22), (0, 20), &config),
+            vec![((0, 10), (0, 10)), ((5, 15), (5, 15)), ((10, 22), (10, 20)),]
+        );
+
+        // a rectangle away from the origin
+        assert_eq!(
+            generate_segments((100, 115), (40, 60), &config),
+            vec![((100, 110), (40, 50)), ((105, 115), (45, 60))]
+        );
+
+        assert!(generate_segments((5, 5), (0, 20), &config).is_empty());
+
+        // without overlap
+        let mut config = config;
+        config.overlap = Overlap::Bases(0);
+        assert_eq!(
+            generate_segments((0, 20), (0, 20), &config),
+            vec![((0, 10), (0, 10)), ((10, 20), (10, 20))]
+        );
+    }
+
+    #[test]
+    fn test_chain() {
+        let block = |line_num, (target_start, target_stop), (query_start, query_stop)| Block {
+            line_num,
+            target_start,
+            target_stop,
+            query_start,
+            query_stop,
+            matches: 0,
+        };
+        let blocks = vec![
+            block(0, (0, 100), (1000, 1100)),
+            block(1, (150, 300), (1150, 1300)),
+            // out of order on the query
+            block(2, (120, 140), (0, 20)),
+            // overlaps the second record
+            block(3, (290, 400), (1290, 1400)),
+        ];
+
+        assert_eq!(
+            chain(&blocks),
+            vec![
+                (0, 1000),
+                (100, 1100),
+                (150, 1150),
+                (300, 1300),
+                (300, 1300),
+                (400, 1400)
+            ]
+        );
+        assert!(chain(&[]).is_empty());
+    }
+
+    #[test]
+    fn test_segment_bases() {
+        let sequences = fasta::test_sequences(">tgt\nAACCGGTTAC\n>qry\nGTAATCGGTT\n");
+
+        let segment = ((2, 6), (2, 6));
+        let names = ("tgt", "qry");
+
+        let (target, query) =
+            segment_bases(&sequences, names, Strand::Forward, 10, segment).unwrap();
+        assert_eq!((&target[..], &query[..]), (&b"CCGG"[..], &b"AATC"[..]));
+
+        // positions in the reverse complement of the query
+        let (_, query) = segment_bases(&sequences, names, Strand::Reverse, 10, segment).unwrap();
+        assert_eq!(&query[..], b"CCGA");
+
+        assert!(segment_bases(&sequences, ("chr1", "qry"), Strand::Forward, 10, segment).is_err());
+    }
+
+    #[test]
+    fn test_generate_path_segments() {
+        let config = test_config();
+
+        // a diagonal away from the main diagonal
+        assert_eq!(
+            generate_path_segments(&[(0, 40), (30, 70)], &config),
+            vec![
+                ((0, 10), (40, 50)),
+                ((5, 15), (45, 55)),
+                ((10, 20), (50, 60)),
+                ((15, 25), (55, 65)),
+                ((20, 30), (60, 70)),
+            ]
+        );
+
+        // an insertion of 10 query bases in the middle
+        assert_eq!(
+            generate_path_segments(&[(0, 0), (10, 10), (10, 20), (20, 30)], &config),
+            vec![
+                ((0, 10), (0, 10)),
+                ((5, 10), (5, 15)),
+                ((10, 15), (15, 25)),
+                ((10, 20), (20, 30)),
+            ]
+        );
+    }
 }
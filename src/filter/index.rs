@@ -1,130 +1,303 @@
-use coitrees;
+use std::collections::HashMap;
 use std::convert::TryFrom;
+use std::io::BufRead;
 use std::str::FromStr;
 
+use super::prefilter;
 use super::types;
+use crate::error::{FieldError, Result};
+use crate::fasta::{self, Sequences};
+use crate::io;
 use crate::paf;
+use crate::report::{Report, Status};
+use crate::types::AppConfig;
 
-// TODO: account for strand & stop
+// Reverse strand CIGARs walk the query from its end towards its start on the
+// original strand. We therefore compute query positions for reverse strand
+// alignments in reverse complement coordinates i.e. a position p on the
+// original strand is at length - p in reverse complement coordinates.
 fn compute_match_intervals(
-    seq_type: types::SequenceType,
-    _strand: types::Strand,
-    start: u32,
-    _stop: u32,
+    strand: types::Strand,
+    query_start: u32,
+    query_stop: u32,
+    query_length: u32,
+    target_start: u32,
+    target_stop: u32,
     cigar: &str,
-    name: &str,
-    line_num: usize,
-) -> Vec<types::Interval> {
-    let mut intervals: Vec<types::Interval> = Vec::new();
+) -> Result<Vec<types::MatchRun>, FieldError> {
+    let mut runs: Vec<types::MatchRun> = Vec::new();
     let mut buffer = String::new();
-    let mut cursor = start;
+    let mut query_cursor = match strand {
+        types::Strand::Forward => query_start,
+        types::Strand::Reverse => query_length - query_stop,
+    };
+    let mut target_cursor = target_start;
+    let query_from = query_cursor;
 
-    cigar.chars().for_each(|c: char| {
+    let parse_len = |buffer: &str, op: char| -> Result<u32, FieldError> {
+        u32::from_str(buffer)
+            .map_err(|_| FieldError::new("cg", &format!("missing length for operation {}", op)))
+    };
+    let advance = |cursor: u32, length: u32| -> Result<u32, FieldError> {
+        cursor
+            .checked_add(length)
+            .ok_or_else(|| FieldError::new("cg", "alignment ends beyond 2^32 - 1"))
+    };
+
+    for c in cigar.chars() {
         match c {
             'M' | '=' => {
-                // TODO: consider the ambiguity of M being match/mismatch
-                let m: u32 = u32::from_str(&buffer[..]).unwrap();
-                intervals.push(types::Interval(cursor, cursor + m, line_num, String::from(name)));
-                cursor += m;
+                // M may hide mismatches, split_mismatches splits them out when we have the bases
+                let m: u32 = parse_len(&buffer[..], c)?;
+                runs.push(types::MatchRun {
+                    query_start: query_cursor,
+                    target_start: target_cursor,
+                    length: m,
+                });
+                query_cursor = advance(query_cursor, m)?;
+                target_cursor = advance(target_cursor, m)?;
                 buffer.clear();
             }
             'X' => {
-                let x: u32 = u32::from_str(&buffer[..]).unwrap();
-                cursor += x;
+                let x: u32 = parse_len(&buffer[..], c)?;
+                query_cursor = advance(query_cursor, x)?;
+                target_cursor = advance(target_cursor, x)?;
                 buffer.clear();
             }
             'I' => {
-                let i: u32 = u32::from_str(&buffer[..]).unwrap();
-                if seq_type == types::SequenceType::Target {
-                    cursor -= i
-                } else {
-                    cursor += i
-                };
+                // insertion to the target consumes only the query
+                let i: u32 = parse_len(&buffer[..], c)?;
+                query_cursor = advance(query_cursor, i)?;
                 buffer.clear();
             }
             'D' => {
-                let d: u32 = u32::from_str(&buffer[..]).unwrap();
-                if seq_type == types::SequenceType::Target {
-                    cursor += d
-                } else {
-                    cursor -= d
-                };
+                // deletion from the target consumes only the target
+                let d: u32 = parse_len(&buffer[..], c)?;
+                target_cursor = advance(target_cursor, d)?;
                 buffer.clear();
             }
             _ => {
                 // At this point we expect the char to be a base 10 digit i.e '0', '1', ..., '9'
                 match c {
                     b if b.is_digit(10) => buffer.push(b),
-                    b if b.is_ascii_alphabetic() => panic!(
-                        "[wffilter::filter::index::coompute_match_intervals] Unexpected char {} in CIGAR string",
-                        b
-                    ),
-                    _ => panic!(
-                        "[wffilter::filter::index::coompute_match_intervals] Unknown char {} in CIGAR string",
-                        c
-                    ),
+                    b if b.is_ascii_alphabetic() => {
+                        return Err(FieldError::new("cg", &format!("unexpected char {}", b)))
+                    }
+                    _ => return Err(FieldError::new("cg", &format!("unknown char {}", c))),
+                }
+            }
+        }
+    }
+
+    if !buffer.is_empty() {
+        return Err(FieldError::new("cg", "ends with a length and no operation"));
+    }
+
+    if i32::try_from(query_cursor).is_err() || i32::try_from(target_cursor).is_err() {
+        return Err(FieldError::new("cg", "alignment ends beyond 2^31 - 1"));
+    }
+
+    // the alignment has to cover the record exactly
+    let spans = [
+        ("query", query_cursor - query_from, query_stop - query_start),
+        (
+            "target",
+            target_cursor - target_start,
+            target_stop - target_start,
+        ),
+    ];
+    for (sequence, consumed, span) in spans.iter() {
+        if consumed != span {
+            return Err(FieldError::new(
+                "cg",
+                &format!(
+                    "covers {} {} bases but the record spans {}",
+                    consumed, sequence, span
+                ),
+            ));
+        }
+    }
+
+    Ok(runs)
+}
+
+// Split match runs where the bases of target and query differ so that only
+// runs of identical bases are left, M runs being matches or mismatches. target
+// and query hold the bases from target_offset and query_offset in the
+// coordinates of the runs.
+fn split_mismatches(
+    runs: Vec<types::MatchRun>,
+    target_offset: u32,
+    target: &[u8],
+    query_offset: u32,
+    query: &[u8],
+) -> Result<Vec<types::MatchRun>, FieldError> {
+    let mut split: Vec<types::MatchRun> = Vec::new();
+
+    for run in runs {
+        let t = (run.target_start - target_offset) as usize;
+        let q = (run.query_start - query_offset) as usize;
+        let length = run.length as usize;
+        if t + length > target.len() || q + length > query.len() {
+            return Err(FieldError::new("cg", "runs past the end of the record"));
+        }
+
+        let mut start: Option<usize> = None;
+        for i in 0..=length {
+            let matches = i < length && target[t + i] == query[q + i] && target[t + i] != b'N';
+            match (matches, start) {
+                (true, None) => start = Some(i),
+                (false, Some(s)) => {
+                    split.push(types::MatchRun {
+                        query_start: run.query_start + s as u32,
+                        target_start: run.target_start + s as u32,
+                        length: (i - s) as u32,
+                    });
+                    start = None;
                 }
+                _ => {}
             }
         }
-    });
+    }
+
+    Ok(split)
+}
+
+// A single match block along the diagonal starting at the alignment start for
+// records that have coordinates but no alignment string. PafAlignment::from_str
+// ensures start <= end on both sequences.
+pub fn approximate_cigar(a: &paf::PafAlignment) -> String {
+    let query_span = a.query_end - a.query_start;
+    let target_span = a.target_end - a.target_start;
+    let block = std::cmp::min(query_span, target_span);
 
-    intervals
+    if query_span > target_span {
+        format!("{}M{}I", block, query_span - block)
+    } else if target_span > query_span {
+        format!("{}M{}D", block, target_span - block)
+    } else {
+        format!("{}M", block)
+    }
 }
 
-pub fn index_paf_matches(p: &paf::PAF) -> types::Index {
-    let alignments: &Vec<paf::PafAlignment> = p.get_alignments();
-    let mut query_intervals: Vec<types::Interval> = Vec::new();
-    let mut target_intervals: Vec<types::Interval> = Vec::new();
-
-    alignments
-        .iter()
-        .enumerate()
-        .for_each(|(line_num, a): (usize, &paf::PafAlignment)| {
-            let mut t = compute_match_intervals(
-                types::SequenceType::Target,
-                a.strand,
-                a.target_start,
-                a.target_end,
-                &a.cigar[..],
+// Stream the PAF and index the match runs of each record as it is parsed
+pub fn index_paf_matches<R: BufRead>(
+    reader: R,
+    config: &AppConfig,
+    sequences: &Sequences,
+    report: &mut Report,
+) -> Result<(paf::PAF, types::Index)> {
+    // stdin can't be read a second time when copying the filtered lines
+    let spool = config.input_paf == io::STDIN;
+    let mut names = types::Names::default();
+    let mut pairs: HashMap<types::PairKey, types::PairIndex> = HashMap::new();
+
+    let visit = |line_num: usize, a: &paf::PafAlignment| -> Result<(), FieldError> {
+        if let Some(status) = prefilter::check(a, &config.prefilter) {
+            report.add(line_num, a, status);
+            return Ok(());
+        }
+
+        let approximate: String;
+        let cigar: &str = match &a.cigar {
+            Some(c) => &c[..],
+            None if config.approximate => {
+                approximate = approximate_cigar(a);
+                &approximate[..]
+            }
+            None => return Err(FieldError::new(
+                "cg",
+                "neither a cg nor a cs field. Use --approximate to index it from its coordinates",
+            )),
+        };
+
+        let runs = compute_match_intervals(
+            a.strand,
+            a.query_start,
+            a.query_end,
+            a.query_length,
+            a.target_start,
+            a.target_end,
+            cigar,
+        )?;
+
+        // M could be a match or a mismatch, tell them apart if we have the bases
+        let runs = if !sequences.is_empty() && cigar.contains('M') {
+            let target = sequences.fetch(
                 &a.target[..],
-                line_num,
-            );
-            let mut q = compute_match_intervals(
-                types::SequenceType::Query,
-                a.strand,
-                a.query_start,
-                a.query_end,
-                &a.cigar[..],
+                a.target_start as u64,
+                a.target_end as u64,
+                "target name",
+            )?;
+            let mut query = sequences.fetch(
                 &a.query[..],
-                line_num,
-            );
+                a.query_start as u64,
+                a.query_end as u64,
+                "query name",
+            )?;
+            let query_offset = match a.strand {
+                types::Strand::Forward => a.query_start,
+                types::Strand::Reverse => {
+                    fasta::reverse_complement(&mut query);
+                    a.query_length - a.query_end
+                }
+            };
 
-            query_intervals.append(&mut q);
-            target_intervals.append(&mut t);
-        });
+            split_mismatches(runs, a.target_start, &target, query_offset, &query)?
+        } else {
+            runs
+        };
 
-    let gen_coitree =
-        |intervals: Vec<types::Interval>| -> coitrees::COITree<types::PafMetadata, u32> {
-            // Generate coitrees::IntervalNodes
-            let interval_nodes: Vec<coitrees::IntervalNode<types::PafMetadata, u32>> =
-                intervals
-                    .iter()
-                    .map(|types::Interval(start, stop, line_num, name): &types::Interval| {
-                        let start = i32::try_from(*start).expect("[wffilter::filter::index::index_paf] Could not convert start u32 to i32");
-                        let end = i32::try_from(*stop).expect("[wffilter::filter::index::index_paf] Could not convert end u32 to i32");
-                        let metadata = types::PafMetadata{line_num: *line_num as u32, name: name.clone()};
+        if a.cigar.is_none() {
+            report.add(line_num, a, Status::Approximate);
+        }
 
-                        coitrees::IntervalNode::<types::PafMetadata, u32>::new(start, end, metadata)
-                    })
-                    .collect();
+        let key = types::PairKey {
+            query: names.intern(&a.query[..]),
+            target: names.intern(&a.target[..]),
+            strand: a.strand,
+        };
 
-            coitrees::COITree::new(interval_nodes)
+        let pair_index = pairs.entry(key).or_default();
+        let (query_start, query_stop) = match a.strand {
+            types::Strand::Forward => (a.query_start, a.query_end),
+            types::Strand::Reverse => {
+                (a.query_length - a.query_end, a.query_length - a.query_start)
+            }
         };
+        pair_index.blocks.push(types::Block {
+            line_num: line_num as u32,
+            target_start: a.target_start,
+            target_stop: a.target_end,
+            query_start,
+            query_stop,
+            matches: runs.iter().map(|run| run.length).sum(),
+        });
 
-    types::Index {
-        target_index: gen_coitree(target_intervals),
-        query_index: gen_coitree(query_intervals),
-    }
+        runs.iter().for_each(|run: &types::MatchRun| {
+            // compute_match_intervals ensures that these fit in an i32
+            let diagonal = run.target_start as i32 - run.query_start as i32;
+            let metadata = types::PafMetadata {
+                line_num: line_num as u32,
+                target_start: run.target_start,
+                target_stop: run.target_start + run.length,
+            };
+
+            pair_index.add_run(diagonal, metadata);
+        });
+
+        Ok(())
+    };
+
+    let paf = paf::PAF::from_reader(
+        reader,
+        &config.input_paf[..],
+        spool,
+        config.skip_invalid,
+        visit,
+    )?;
+
+    Ok((paf, types::Index { names, pairs }))
 }
 
 #[cfg(test)]
@@ -140,72 +313,192 @@ mod tests {
         #[test]
         fn test_compute_match_intervals_tiny() {
             // Forward
-            let intervals_computed: Vec<types::Interval> = compute_match_intervals(
-                types::SequenceType::Query,
+            let runs_computed: Vec<types::MatchRun> = compute_match_intervals(
                 types::Strand::Forward,
                 0,
                 330243,
-                "330243M",
-                "no_name",
+                330243,
                 0,
+                330243,
+                "330243M",
+            )
+            .unwrap();
+            let runs: Vec<types::MatchRun> = vec![types::MatchRun {
+                query_start: 0,
+                target_start: 0,
+                length: 330243,
+            }];
+            assert_eq!(runs, runs_computed);
+        }
+
+        #[test]
+        fn test_compute_match_intervals_reverse() {
+            // query 100..130 of a 200bp query on the reverse strand
+            // is 70..100 in reverse complement coordinates
+            // the target is always on the forward strand
+            let runs_computed: Vec<types::MatchRun> =
+                compute_match_intervals(types::Strand::Reverse, 100, 130, 200, 10, 40, "10=5X15=")
+                    .unwrap();
+            let runs: Vec<types::MatchRun> = vec![
+                types::MatchRun {
+                    query_start: 70,
+                    target_start: 10,
+                    length: 10,
+                },
+                types::MatchRun {
+                    query_start: 85,
+                    target_start: 25,
+                    length: 15,
+                },
+            ];
+            assert_eq!(runs, runs_computed);
+        }
+
+        #[test]
+        fn test_compute_match_intervals_indels() {
+            let runs_computed: Vec<types::MatchRun> =
+                compute_match_intervals(types::Strand::Forward, 5, 32, 100, 0, 28, "10M2I10M3D5M")
+                    .unwrap();
+            let runs: Vec<types::MatchRun> = vec![
+                types::MatchRun {
+                    query_start: 5,
+                    target_start: 0,
+                    length: 10,
+                },
+                types::MatchRun {
+                    query_start: 17,
+                    target_start: 10,
+                    length: 10,
+                },
+                types::MatchRun {
+                    query_start: 27,
+                    target_start: 23,
+                    length: 5,
+                },
+            ];
+            assert_eq!(runs, runs_computed);
+        }
+
+        #[test]
+        fn test_compute_match_intervals_invalid() {
+            let error = |query_stop: u32, target_stop: u32, cigar: &str| -> String {
+                compute_match_intervals(
+                    types::Strand::Forward,
+                    0,
+                    query_stop,
+                    u32::MAX,
+                    0,
+                    target_stop,
+                    cigar,
+                )
+                .err()
+                .unwrap()
+                .to_string()
+            };
+
+            // lengths that don't fit in a u32
+            assert_eq!(
+                "invalid cg: alignment ends beyond 2^32 - 1",
+                error(100, 100, "4294967290M10M")
+            );
+            // more or fewer bases than the record spans
+            assert_eq!(
+                "invalid cg: covers 25 query bases but the record spans 20",
+                error(20, 20, "10M5I10M")
+            );
+            assert_eq!(
+                "invalid cg: covers 20 target bases but the record spans 25",
+                error(20, 25, "10M10M")
             );
-            let intervals: Vec<types::Interval> =
-                vec![types::Interval(0, 330243, 0, String::from("no_name"))];
-            assert_eq!(intervals, intervals_computed);
         }
 
-        #[ignore]
         #[test]
-        fn test_compute_match_intervals_long_query() {
-            let intervals_computed: Vec<types::Interval> = compute_match_intervals(
-                types::SequenceType::Query,
-                types::Strand::Forward,
-                41052,
-                324759,
-                TEST_CIGAR_1,
-                "test_cigar_1",
-                0,
+        fn test_split_mismatches() {
+            let runs = vec![
+                types::MatchRun {
+                    query_start: 100,
+                    target_start: 10,
+                    length: 6,
+                },
+                types::MatchRun {
+                    query_start: 107,
+                    target_start: 16,
+                    length: 2,
+                },
+            ];
+            // the first run has a mismatch and an N, the second matches
+            let target = b"ACGTNAGG";
+            let query = b"ACTTNAXGG";
+
+            let split = split_mismatches(runs, 10, target, 100, query).unwrap();
+            let expected: Vec<(u32, u32, u32)> =
+                vec![(100, 10, 2), (103, 13, 1), (105, 15, 1), (107, 16, 2)];
+            assert_eq!(
+                expected,
+                split
+                    .iter()
+                    .map(|r| (r.query_start, r.target_start, r.length))
+                    .collect::<Vec<(u32, u32, u32)>>()
             );
-            let intervals: Vec<types::Interval> = vec![];
-            assert_eq!(intervals, intervals_computed);
+
+            // more runs than bases
+            let runs = vec![types::MatchRun {
+                query_start: 100,
+                target_start: 10,
+                length: 9,
+            }];
+            assert!(split_mismatches(runs, 10, target, 100, query).is_err());
         }
 
-        #[ignore]
         #[test]
-        fn test_compute_match_intervals_long_target() {
-            let intervals_computed: Vec<types::Interval> = compute_match_intervals(
-                types::SequenceType::Target,
+        fn test_compute_match_intervals_long() {
+            let runs: Vec<types::MatchRun> = compute_match_intervals(
                 types::Strand::Forward,
+                41052,
+                324759,
+                329347,
                 0,
                 283680,
                 TEST_CIGAR_1,
-                "test_cigar_1",
-                0,
+            )
+            .unwrap();
+
+            assert_eq!(37, runs.len());
+
+            let first = runs.first().unwrap();
+            assert_eq!(
+                (41052, 0, 15),
+                (first.query_start, first.target_start, first.length)
             );
-            let intervals: Vec<types::Interval> = vec![];
-            assert_eq!(intervals, intervals_computed);
+
+            // the last run ends where the alignment does
+            let last = runs.last().unwrap();
+            assert_eq!(324759, last.query_start + last.length);
+            assert_eq!(283680, last.target_start + last.length);
         }
 
         // TODO: is this cigar even valid?
         #[ignore]
         #[test]
         fn test_failing() {
-            let intervals_computed: Vec<types::Interval> = compute_match_intervals(
-                types::SequenceType::Query,
+            let runs_computed: Vec<types::MatchRun> = compute_match_intervals(
                 types::Strand::Forward,
                 0,
                 11068,
-                TEST_CIGAR_2,
-                "test_cigar_2",
+                11068,
                 0,
-            );
-            let intervals: Vec<types::Interval> = vec![];
-            assert_eq!(intervals, intervals_computed);
+                11068,
+                TEST_CIGAR_2,
+            )
+            .unwrap();
+            let runs: Vec<types::MatchRun> = vec![];
+            assert_eq!(runs, runs_computed);
         }
     }
 
     mod index {
         use super::*;
+        use crate::types::test_config;
 
         #[test]
         fn test_index_paf() {
@@ -214,16 +507,220 @@ mod tests {
             \n\
             qry\t329347\t41052\t324759\t+\ttgt\t283680\t0\t283680\t283613\t283736\t0\tNM:i:123\tms:i:566760\tAS:i:566760\tnn:i:0\ttp:A:S\tcm:i:53397\ts1:i:282348\tde:f:0.0003\trl:i:2765\tcg:Z:15M1I158M1I24M1I169M1I1147M1I24M1I851M1I13M1I3900M1D25M1I874M4I10847M3D4400M1I1494M1D4041M1I8577M14I1340M2D21138M2I7776M6D3563M2I83120M10D5541M2D27729M1I2M13I49698M1I5030M2I17541M1D22531M1I187M1D458M1D80M1I75M1I266M1I48M1I269M1I460M1D240M
 ";
-            let alignments: paf::PAF = paf::PAF::from_str(TEST_PAF_STRING);
-            let index = index_paf_matches(&alignments);
-            let query_index = index.query_index;
-            let target_index = index.target_index;
-
-            // should apply to all of them
-            assert_eq!(38, query_index.query_count(0, 330_243));
-            // the first match in the second alignment plus the first alignment which covers everything
-            assert_eq!(2, query_index.query_count(41_052, 41_067));
-            assert_eq!(query_index.len(), target_index.len());
+            let (_, index) = index_paf_matches(
+                TEST_PAF_STRING.as_bytes(),
+                &test_config(),
+                &Sequences::default(),
+                &mut Report::default(),
+            )
+            .unwrap();
+
+            assert_eq!(2, index.names.len());
+            assert_eq!(1, index.pairs.len());
+            assert!(index.get("qry", "tgt", types::Strand::Reverse).is_none());
+
+            let pair_index = index.get("qry", "tgt", types::Strand::Forward).unwrap();
+            // the first alignment is a single run and the second has 37
+            assert_eq!(38, pair_index.run_count());
+
+            let lines_at = |v: u32, h: u32| -> Vec<u32> {
+                let mut lines: Vec<u32> = Vec::new();
+                pair_index.query_cell(v, h, |m| lines.push(m.line_num));
+                lines.sort();
+                lines
+            };
+
+            // on the main diagonal only the first alignment matches
+            assert_eq!(vec![0], lines_at(100, 100));
+            // the first match of the second alignment
+            assert_eq!(vec![1], lines_at(41_052, 0));
+            assert_eq!(vec![1], lines_at(41_066, 14));
+            // the inserted query base is not a match
+            assert_eq!(Vec::<u32>::new(), lines_at(41_067, 15));
+            assert_eq!(vec![1], lines_at(41_068, 15));
+            // off diagonal
+            assert_eq!(Vec::<u32>::new(), lines_at(41_052, 1));
+        }
+
+        #[test]
+        fn test_query_run() {
+            let (_, index) = index_paf_matches(
+                "qry\t100\t0\t30\t+\ttgt\t100\t0\t30\t25\t30\t60\tcg:Z:10=5X15=".as_bytes(),
+                &test_config(),
+                &Sequences::default(),
+                &mut Report::default(),
+            )
+            .unwrap();
+            let pair_index = index.get("qry", "tgt", types::Strand::Forward).unwrap();
+
+            let mut overlaps: Vec<(u32, u32, u32)> = Vec::new();
+            pair_index.query_run(5, 5, 20, |m, start, stop| {
+                overlaps.push((m.line_num, start, stop))
+            });
+            overlaps.sort();
+
+            assert_eq!(vec![(0, 5, 10), (0, 15, 25)], overlaps);
+        }
+
+        #[test]
+        fn test_query_many_runs_on_a_diagonal() {
+            // 20,000 runs of 1 match and 1 mismatch on the main diagonal and
+            // a second record with a single run over part of them
+            let cigar = "1=1X".repeat(20_000);
+            let paf_string = format!(
+                "qry\t40000\t0\t40000\t+\ttgt\t40000\t0\t40000\t20000\t40000\t60\tcg:Z:{}\n\
+                 qry\t40000\t100\t200\t+\ttgt\t40000\t100\t200\t100\t100\t60\tcg:Z:100=\n",
+                cigar
+            );
+            let (_, index) = index_paf_matches(
+                paf_string.as_bytes(),
+                &test_config(),
+                &Sequences::default(),
+                &mut Report::default(),
+            )
+            .unwrap();
+            let pair_index = index.get("qry", "tgt", types::Strand::Forward).unwrap();
+            assert_eq!(20_001, pair_index.run_count());
+
+            // every cell of the diagonal, the matches are on even positions
+            for h in 0..40_000 {
+                let mut lines: Vec<u32> = Vec::new();
+                pair_index.query_cell(h, h, |m| lines.push(m.line_num));
+                lines.sort();
+
+                let mut expected: Vec<u32> = Vec::new();
+                if h % 2 == 0 {
+                    expected.push(0);
+                }
+                if (100..200).contains(&h) {
+                    expected.push(1);
+                }
+                assert_eq!(expected, lines);
+            }
+
+            let mut overlaps: Vec<(u32, u32, u32)> = Vec::new();
+            pair_index.query_run(195, 195, 10, |m, start, stop| {
+                overlaps.push((m.line_num, start, stop))
+            });
+            overlaps.sort();
+            assert_eq!(
+                vec![
+                    (0, 196, 197),
+                    (0, 198, 199),
+                    (0, 200, 201),
+                    (0, 202, 203),
+                    (0, 204, 205),
+                    (1, 195, 200)
+                ],
+                overlaps
+            );
+        }
+
+        #[test]
+        fn test_index_approximate() {
+            // 40 query bases against 50 target bases without an alignment string
+            let mapping = "qry\t100\t10\t50\t+\ttgt\t100\t0\t50\t0\t50\t255";
+            let mut report = Report::default();
+            let (_, index) = index_paf_matches(
+                mapping.as_bytes(),
+                &AppConfig {
+                    approximate: true,
+                    ..test_config()
+                },
+                &Sequences::default(),
+                &mut report,
+            )
+            .unwrap();
+            let pair_index = index.get("qry", "tgt", types::Strand::Forward).unwrap();
+
+            let mut overlaps: Vec<(u32, u32, u32)> = Vec::new();
+            pair_index.query_run(10, 0, 50, |m, start, stop| {
+                overlaps.push((m.line_num, start, stop))
+            });
+
+            assert_eq!(vec![(0, 0, 40)], overlaps);
+            assert_eq!(1, report.count(Status::Approximate));
+
+            // inverted coordinates are an error rather than a negative block
+            let inverted = "qry\t100\t50\t10\t+\ttgt\t100\t0\t50\t0\t50\t255";
+            let e = index_paf_matches(
+                inverted.as_bytes(),
+                &AppConfig {
+                    approximate: true,
+                    ..test_config()
+                },
+                &Sequences::default(),
+                &mut Report::default(),
+            )
+            .err()
+            .unwrap();
+            assert_eq!(
+                format!("{}", e),
+                "test.paf:1: invalid query start: 50 is after the end 10"
+            );
+        }
+
+        #[test]
+        fn test_index_prefilter() {
+            let paf_string = "\
+            qry\t100\t0\t30\t+\ttgt\t100\t0\t30\t30\t30\t60\tcg:Z:30M\n\
+            qry\t100\t0\t30\t+\ttgt\t100\t50\t80\t30\t30\t0\tcg:Z:30M\n";
+            let mut config = test_config();
+            config.prefilter.min_mapping_quality = 1;
+            let mut report = Report::default();
+            let (paf, index) = index_paf_matches(
+                paf_string.as_bytes(),
+                &config,
+                &Sequences::default(),
+                &mut report,
+            )
+            .unwrap();
+            let pair_index = index.get("qry", "tgt", types::Strand::Forward).unwrap();
+
+            // the removed record keeps its place in the PAF but isn't indexed
+            assert_eq!(2, paf.get_offsets().len());
+            assert_eq!(1, pair_index.run_count());
+            assert_eq!(1, report.count(Status::LowMappingQuality));
+        }
+
+        #[test]
+        fn test_index_split_mismatches() {
+            // the reverse complement of the query differs from the target at 5
+            let sequences = fasta::test_sequences(">tgt\nAACCGGTTAC\n>qry\nGTAATCGGTT\n");
+
+            let (_, index) = index_paf_matches(
+                "qry\t10\t0\t10\t-\ttgt\t10\t0\t10\t9\t10\t60\tcg:Z:10M".as_bytes(),
+                &test_config(),
+                &sequences,
+                &mut Report::default(),
+            )
+            .unwrap();
+            let pair_index = index.get("qry", "tgt", types::Strand::Reverse).unwrap();
+
+            let mut overlaps: Vec<(u32, u32)> = Vec::new();
+            pair_index.query_run(0, 0, 10, |_, start, stop| overlaps.push((start, stop)));
+            overlaps.sort();
+
+            assert_eq!(vec![(0, 5), (6, 10)], overlaps);
+        }
+
+        #[test]
+        fn test_index_without_alignment_string() {
+            let mapping = "qry\t100\t10\t50\t+\ttgt\t100\t0\t50\t0\t50\t255";
+            let result = index_paf_matches(
+                mapping.as_bytes(),
+                &test_config(),
+                &Sequences::default(),
+                &mut Report::default(),
+            );
+
+            match result {
+                Err(e) => assert_eq!(
+                    format!("{}", e),
+                    "test.paf:1: invalid cg: neither a cg nor a cs field. Use --approximate to index it from its coordinates"
+                ),
+                Ok(_) => panic!("expected an error"),
+            }
         }
     }
 }
@@ -1,3 +1,7 @@
+pub mod alignment;
 pub mod filter;
 pub mod index;
+pub mod prefilter;
+pub mod support;
+pub mod trim;
 pub mod types;
@@ -0,0 +1,157 @@
+/*
+Drop PAF records that are not worth indexing before they reach the wavefront
+step e.g. low mapping quality secondaries. Removed records are not indexed and
+therefore can never be kept, the report lists them with the reason.
+*/
+
+use std::str::FromStr;
+
+use crate::paf::PafAlignment;
+use crate::report::Status;
+use crate::types::Prefilter;
+
+// Gap-compressed identity i.e. a gap of any length counts as a single
+// difference. Taken from de:f when present otherwise computed from the CIGAR
+// with mismatches from X operations or NM for CIGARs that only use M.
+// None if neither is available.
+pub fn gap_compressed_identity(a: &PafAlignment) -> Option<f64> {
+    if let Some(de) = a.tag_float("de") {
+        return Some(1.0 - de);
+    }
+
+    let cigar = a.cigar.as_ref()?;
+    let mut columns: u64 = 0; // M, = and X
+    let mut mismatches: u64 = 0;
+    let mut gap_bases: u64 = 0;
+    let mut gap_opens: u64 = 0;
+    let mut extended = false; // uses = and X instead of M
+
+    let mut buffer = String::new();
+    for c in cigar.chars() {
+        if c.is_ascii_digit() {
+            buffer.push(c);
+            continue;
+        }
+
+        let n = u64::from_str(&buffer[..]).ok()?;
+        buffer.clear();
+        match c {
+            'M' => columns += n,
+            '=' => {
+                columns += n;
+                extended = true;
+            }
+            'X' => {
+                columns += n;
+                mismatches += n;
+                extended = true;
+            }
+            'I' | 'D' => {
+                gap_bases += n;
+                gap_opens += 1;
+            }
+            _ => return None,
+        }
+    }
+
+    if !extended {
+        // NM counts every base of a gap
+        mismatches = (a.tag_int("NM")? as u64).checked_sub(gap_bases)?;
+    }
+
+    if columns + gap_opens == 0 {
+        return None;
+    }
+
+    Some(1.0 - (mismatches + gap_opens) as f64 / (columns + gap_opens) as f64)
+}
+
+// The reason to remove a, if any. Records we can't tell the identity of are kept.
+pub fn check(a: &PafAlignment, prefilter: &Prefilter) -> Option<Status> {
+    if a.mapping_quality < prefilter.min_mapping_quality {
+        return Some(Status::LowMappingQuality);
+    }
+
+    if a.block_length < prefilter.min_block_length {
+        return Some(Status::ShortBlock);
+    }
+
+    if prefilter.primary_only && matches!(a.tag_char("tp"), Some(tp) if tp != 'P') {
+        return Some(Status::NotPrimary);
+    }
+
+    if prefilter.min_identity > 0.0 {
+        match gap_compressed_identity(a) {
+            Some(identity) if identity < prefilter.min_identity => {
+                return Some(Status::LowIdentity)
+            }
+            _ => {}
+        }
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static TEST_PAF_STRING: &str =
+        "qry\t100\t0\t50\t+\ttgt\t100\t0\t50\t45\t52\t60\tNM:i:5\ttp:A:P\tcg:Z:20M1I10M1D19M";
+
+    fn alignment(line: &str) -> PafAlignment {
+        PafAlignment::from_str(line).unwrap()
+    }
+
+    #[test]
+    fn test_gap_compressed_identity() {
+        // 3 mismatches and 2 gap opens over 49 columns and 2 gaps
+        let a = alignment(TEST_PAF_STRING);
+        assert_eq!(gap_compressed_identity(&a), Some(1.0 - 5.0 / 51.0));
+
+        // the same from = and X
+        let a = alignment(&TEST_PAF_STRING.replace("20M1I10M1D19M", "18=2X1I10=1D18=1X")[..]);
+        assert_eq!(gap_compressed_identity(&a), Some(1.0 - 5.0 / 51.0));
+
+        // de takes precedence
+        let a = alignment(&format!("{}\tde:f:0.25", TEST_PAF_STRING)[..]);
+        assert_eq!(gap_compressed_identity(&a), Some(0.75));
+
+        // M without NM
+        let a = alignment(&TEST_PAF_STRING.replace("NM:i:5\t", "")[..]);
+        assert_eq!(gap_compressed_identity(&a), None);
+    }
+
+    #[test]
+    fn test_check() {
+        let a = alignment(TEST_PAF_STRING);
+        let prefilter = |f: fn(&mut Prefilter)| {
+            let mut p = Prefilter::default();
+            f(&mut p);
+            check(&a, &p)
+        };
+
+        assert_eq!(prefilter(|_| {}), None);
+        assert_eq!(
+            prefilter(|p| p.min_mapping_quality = 61),
+            Some(Status::LowMappingQuality)
+        );
+        assert_eq!(
+            prefilter(|p| p.min_block_length = 53),
+            Some(Status::ShortBlock)
+        );
+        assert_eq!(
+            prefilter(|p| p.min_identity = 0.95),
+            Some(Status::LowIdentity)
+        );
+        assert_eq!(prefilter(|p| p.min_identity = 0.9), None);
+        assert_eq!(prefilter(|p| p.primary_only = true), None);
+
+        let secondary = alignment(&TEST_PAF_STRING.replace("tp:A:P", "tp:A:S")[..]);
+        let p = Prefilter {
+            primary_only: true,
+            ..Prefilter::default()
+        };
+        assert_eq!(check(&secondary, &p), Some(Status::NotPrimary));
+    }
+}
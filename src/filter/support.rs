@@ -0,0 +1,247 @@
+/*
+How much of each indexed PAF record the global alignment runs along. A record
+is kept if its support reaches --min-support-bases and --min-support-fraction.
+The support of every indexed record can be written out to tune them, records
+are identified by their line in the PAF as in the report.
+
+|Col|Type   |Description                                       |
+|--:|:-----:|:-------------------------------------------------|
+|1  |int    |Line number                                       |
+|2  |int    |Bases of its match runs on the global alignment   |
+|3  |int    |Bases in its match runs                           |
+|4  |float  |Fraction of the bases in its match runs on the path|
+|5  |string |Status: kept or removed_support                   |
+*/
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::io::{self, BufWriter, Write};
+
+use super::types::{Index, QueryResult};
+use crate::error::{Error, Result};
+use crate::types::MinSupport;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Support {
+    pub bases: u32,   // bases of the match runs of the record on the path
+    pub matches: u32, // bases in the match runs of the record
+}
+
+impl Support {
+    pub fn fraction(&self) -> f64 {
+        if self.matches == 0 {
+            return 0.0;
+        }
+
+        self.bases as f64 / self.matches as f64
+    }
+
+    // records the global alignment never runs along are never kept
+    pub fn passes(&self, min_support: &MinSupport) -> bool {
+        self.bases > 0 && self.bases >= min_support.bases && self.fraction() >= min_support.fraction
+    }
+}
+
+// The support of every indexed record by record number. Overlapping segments
+// and mappings report the same bases more than once so overlaps are merged.
+pub fn record_support(
+    index: &Index,
+    all_matching_regions: &[HashSet<QueryResult>],
+) -> BTreeMap<usize, Support> {
+    let mut support: BTreeMap<usize, Support> = BTreeMap::new();
+    for block in index.pairs.values().flat_map(|p| p.blocks.iter()) {
+        support.entry(block.line_num as usize).or_default().matches = block.matches;
+    }
+
+    let mut overlaps: HashMap<usize, Vec<(u32, u32)>> = HashMap::new();
+    for region in all_matching_regions.iter().flatten() {
+        overlaps
+            .entry(region.line as usize)
+            .or_default()
+            .push((region.sequence_start as u32, region.sequence_stop as u32));
+    }
+
+    for (line, mut overlaps) in overlaps {
+        overlaps.sort_unstable();
+
+        let mut bases: u32 = 0;
+        let mut covered: u32 = 0; // the furthest position counted so far
+        for (start, stop) in overlaps {
+            let start = std::cmp::max(start, covered);
+            if start < stop {
+                bases += stop - start;
+                covered = stop;
+            }
+        }
+
+        support.entry(line).or_default().bases = bases;
+    }
+
+    support
+}
+
+// The records whose support passes min_support in order
+pub fn supported_lines(support: &BTreeMap<usize, Support>, min_support: &MinSupport) -> Vec<usize> {
+    support
+        .iter()
+        .filter(|(_, s)| s.passes(min_support))
+        .map(|(line, _)| *line)
+        .collect()
+}
+
+// line_numbers holds the line in the PAF of each record
+pub fn write<W: Write>(
+    support: &BTreeMap<usize, Support>,
+    min_support: &MinSupport,
+    line_numbers: &[usize],
+    writer: &mut W,
+) -> io::Result<()> {
+    for (record, s) in support {
+        let status = if s.passes(min_support) {
+            "kept"
+        } else {
+            "removed_support"
+        };
+        writeln!(
+            writer,
+            "{}\t{}\t{}\t{:.4}\t{}",
+            line_numbers[*record],
+            s.bases,
+            s.matches,
+            s.fraction(),
+            status
+        )?;
+    }
+
+    writer.flush()
+}
+
+pub fn to_file(
+    support: &BTreeMap<usize, Support>,
+    min_support: &MinSupport,
+    line_numbers: &[usize],
+    fp: &str,
+) -> Result<()> {
+    fs::File::create(fp)
+        .and_then(|file| {
+            write(
+                support,
+                min_support,
+                line_numbers,
+                &mut BufWriter::new(file),
+            )
+        })
+        .map_err(|e| Error::io(fp, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fasta::Sequences;
+    use crate::filter::index::index_paf_matches;
+    use crate::report::Report;
+    use crate::types::test_config;
+
+    #[test]
+    fn test_passes() {
+        let support = Support {
+            bases: 30,
+            matches: 120,
+        };
+
+        assert!(support.passes(&MinSupport::default()));
+        assert!(!support.passes(&MinSupport {
+            bases: 31,
+            fraction: 0.0
+        }));
+        assert!(support.passes(&MinSupport {
+            bases: 30,
+            fraction: 0.25
+        }));
+        assert!(!support.passes(&MinSupport {
+            bases: 1,
+            fraction: 0.3
+        }));
+        assert!(!Support::default().passes(&MinSupport {
+            bases: 0,
+            fraction: 0.0
+        }));
+    }
+
+    #[test]
+    fn test_record_support() {
+        let config = test_config();
+        let paf = "qry\t100\t0\t30\t+\ttgt\t100\t0\t30\t25\t30\t60\tcg:Z:10=5X15=\n\
+                   qry\t100\t50\t60\t+\ttgt\t100\t50\t60\t10\t10\t60\tcg:Z:10=";
+        let (_, index) = index_paf_matches(
+            paf.as_bytes(),
+            &config,
+            &Sequences::default(),
+            &mut Report::default(),
+        )
+        .unwrap();
+
+        let region = |line, sequence_start, sequence_stop, segment_tstart| QueryResult {
+            line,
+            sequence_start,
+            sequence_stop,
+            segment_qstart: segment_tstart,
+            segment_qstop: segment_tstart + 10,
+            segment_tstart,
+            segment_tstop: segment_tstart + 10,
+        };
+        // overlapping segments report bases 5..10 twice
+        let regions: Vec<HashSet<QueryResult>> = vec![
+            vec![region(0, 0, 10, 0), region(0, 5, 10, 5)]
+                .into_iter()
+                .collect(),
+            vec![region(0, 15, 20, 15)].into_iter().collect(),
+        ];
+
+        let support = record_support(&index, &regions);
+        assert_eq!(
+            support.get(&0),
+            Some(&Support {
+                bases: 15,
+                matches: 25
+            })
+        );
+        assert_eq!(
+            support.get(&1),
+            Some(&Support {
+                bases: 0,
+                matches: 10
+            })
+        );
+    }
+
+    #[test]
+    fn test_write_support() {
+        let mut support: BTreeMap<usize, Support> = BTreeMap::new();
+        support.insert(
+            2,
+            Support {
+                bases: 0,
+                matches: 10,
+            },
+        );
+        support.insert(
+            0,
+            Support {
+                bases: 5,
+                matches: 8,
+            },
+        );
+
+        // records 0 and 2 are on lines 1 and 5 of the PAF
+        let line_numbers = vec![1, 2, 5];
+        let mut output: Vec<u8> = Vec::new();
+        write(&support, &MinSupport::default(), &line_numbers, &mut output).unwrap();
+
+        assert_eq!(supported_lines(&support, &MinSupport::default()), vec![0]);
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "1\t5\t8\t0.6250\tkept\n5\t0\t10\t0.0000\tremoved_support\n"
+        );
+    }
+}
@@ -0,0 +1,289 @@
+/*
+Clip kept PAF records to the part of the target the global alignment supports,
+see --trim. The clipped record starts and ends on an aligned column rather than
+a gap and its coordinates, residue matches, block length, NM and cg are
+rewritten. cs is dropped in favour of cg. The scores and divergences AS, ms, de
+and dv describe the whole record so they are dropped as well, other fields are
+copied as they are.
+
+M operations are resolved into = and X from the FASTA when there is one,
+otherwise they count as matches the same way they are indexed.
+*/
+
+use std::str::FromStr;
+
+use super::index;
+use super::types::Strand;
+use crate::error::FieldError;
+use crate::fasta::{self, Sequences};
+use crate::paf::PafAlignment;
+
+// A CIGAR operation and its length
+type Op = (u32, char);
+
+fn parse_cigar(cigar: &str) -> Result<Vec<Op>, FieldError> {
+    let mut ops: Vec<Op> = Vec::new();
+    let mut buffer = String::new();
+
+    for c in cigar.chars() {
+        if c.is_ascii_digit() {
+            buffer.push(c);
+            continue;
+        }
+        if !"M=XID".contains(c) {
+            return Err(FieldError::new("cg", &format!("unexpected char {}", c)));
+        }
+
+        let n = u32::from_str(&buffer[..])
+            .map_err(|_| FieldError::new("cg", &format!("missing length for operation {}", c)))?;
+        ops.push((n, c));
+        buffer.clear();
+    }
+
+    Ok(ops)
+}
+
+fn target_length(ops: &[Op]) -> u32 {
+    ops.iter()
+        .filter(|(_, op)| *op != 'I')
+        .map(|(n, _)| n)
+        .sum()
+}
+
+fn query_length(ops: &[Op]) -> u32 {
+    ops.iter()
+        .filter(|(_, op)| *op != 'D')
+        .map(|(n, _)| n)
+        .sum()
+}
+
+// Append an operation merging it with the last one if they are the same
+fn push(ops: &mut Vec<Op>, n: u32, op: char) {
+    if n == 0 {
+        return;
+    }
+    match ops.last_mut() {
+        Some((m, last)) if *last == op => *m += n,
+        _ => ops.push((n, op)),
+    }
+}
+
+// The operations of an alignment starting at target position target_offset
+// that fall within start..stop on the target along with the target position
+// and the query offset, from the start of the alignment, the first of them
+// starts at. Gaps at either end are dropped. None if nothing is left.
+fn clip(
+    ops: &[Op],
+    target_offset: u32,
+    (start, stop): (u32, u32),
+) -> Option<(Vec<Op>, (u32, u32))> {
+    let mut clipped: Vec<Op> = Vec::new();
+    let mut begin: Option<(u32, u32)> = None;
+
+    let mut t = target_offset;
+    let mut q: u32 = 0;
+    for &(n, op) in ops {
+        if op == 'I' {
+            // only insertions between kept columns
+            if begin.is_some() && t < stop {
+                push(&mut clipped, n, op);
+            }
+            q += n;
+            continue;
+        }
+
+        let overlap_start = std::cmp::max(t, start);
+        let overlap_stop = std::cmp::min(t + n, stop);
+        if overlap_start < overlap_stop {
+            if begin.is_none() && op != 'D' {
+                begin = Some((overlap_start, q + overlap_start - t));
+            }
+            if begin.is_some() {
+                push(&mut clipped, overlap_stop - overlap_start, op);
+            }
+        }
+
+        t += n;
+        if op != 'D' {
+            q += n;
+        }
+    }
+
+    while let Some((_, 'I')) | Some((_, 'D')) = clipped.last() {
+        clipped.pop();
+    }
+
+    if clipped.is_empty() {
+        return None;
+    }
+
+    begin.map(|b| (clipped, b))
+}
+
+// Replace M operations with = and X by comparing target and query, the bases
+// the operations span in the direction of the alignment. N matches nothing.
+fn resolve(ops: Vec<Op>, target: &[u8], query: &[u8]) -> Vec<Op> {
+    let mut resolved: Vec<Op> = Vec::new();
+    let (mut t, mut q) = (0, 0);
+
+    for (n, op) in ops {
+        if op == 'M' {
+            for i in 0..n as usize {
+                let matches = target[t + i] == query[q + i] && target[t + i] != b'N';
+                push(&mut resolved, 1, if matches { '=' } else { 'X' });
+            }
+        } else {
+            push(&mut resolved, n, op);
+        }
+
+        if op != 'I' {
+            t += n as usize;
+        }
+        if op != 'D' {
+            q += n as usize;
+        }
+    }
+
+    resolved
+}
+
+// The PAF record line clipped to target_start..target_stop. The line is
+// returned as it is if it doesn't reach into the span.
+pub fn trim(line: &str, span: (u32, u32), sequences: &Sequences) -> Result<String, FieldError> {
+    let a = PafAlignment::from_str(line)?;
+    let cigar = match &a.cigar {
+        Some(cigar) => cigar.clone(),
+        None => index::approximate_cigar(&a),
+    };
+
+    let (ops, (target_start, query_offset)) =
+        match clip(&parse_cigar(&cigar[..])?, a.target_start, span) {
+            Some(clipped) => clipped,
+            None => return Ok(String::from(line)),
+        };
+    let target_stop = target_start + target_length(&ops);
+    let query_span = query_length(&ops);
+
+    // reverse strand alignments walk the query from its end
+    let (query_start, query_stop) = match a.strand {
+        Strand::Forward => (
+            a.query_start + query_offset,
+            a.query_start + query_offset + query_span,
+        ),
+        Strand::Reverse => (
+            a.query_end - query_offset - query_span,
+            a.query_end - query_offset,
+        ),
+    };
+
+    let ops = if !sequences.is_empty() && ops.iter().any(|(_, op)| *op == 'M') {
+        let target = sequences.fetch(
+            &a.target,
+            target_start as u64,
+            target_stop as u64,
+            "target name",
+        )?;
+        let mut query = sequences.fetch(
+            &a.query,
+            query_start as u64,
+            query_stop as u64,
+            "query name",
+        )?;
+        if a.strand == Strand::Reverse {
+            fasta::reverse_complement(&mut query);
+        }
+        resolve(ops, &target, &query)
+    } else {
+        ops
+    };
+
+    let count = |wanted: &str| -> u32 {
+        ops.iter()
+            .filter(|(_, op)| wanted.contains(*op))
+            .map(|(n, _)| n)
+            .sum()
+    };
+    let cigar: String = ops.iter().map(|(n, op)| format!("{}{}", n, op)).collect();
+
+    // split like PafAlignment::from_str did, so there are at least 12 columns
+    let mut columns: Vec<String> = line.split_whitespace().map(String::from).collect();
+    columns[2] = query_start.to_string();
+    columns[3] = query_stop.to_string();
+    columns[7] = target_start.to_string();
+    columns[8] = target_stop.to_string();
+    columns[9] = count("M=").to_string();
+    columns[10] = count("M=XID").to_string();
+    // tags that no longer describe the clipped record
+    let stale = ["cg:", "cs:", "NM:", "AS:", "ms:", "de:", "dv:"];
+    columns.retain(|c| !stale.iter().any(|tag| c.starts_with(tag)));
+    columns.push(format!("NM:i:{}", count("XID")));
+    columns.push(format!("cg:Z:{}", cigar));
+
+    Ok(columns.join("\t"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static TEST_PAF_STRING: &str =
+        "qry\t100\t10\t40\t+\ttgt\t200\t50\t80\t26\t32\t60\tNM:i:6\tAS:i:40\ttp:A:P\tde:f:0.0588\tcg:Z:10=2I8=1X5=4D2=";
+
+    #[test]
+    fn test_clip() {
+        let ops = parse_cigar("10=2I8=1X5=4D2=").unwrap();
+
+        // the insertion at the start and the deletion at the end are dropped
+        assert_eq!(
+            clip(&ops, 50, (60, 74)),
+            Some((vec![(8, '='), (1, 'X'), (5, '=')], (60, 12)))
+        );
+        assert_eq!(
+            clip(&ops, 50, (55, 62)),
+            Some((vec![(5, '='), (2, 'I'), (2, '=')], (55, 5)))
+        );
+        assert_eq!(clip(&ops, 50, (74, 78)), None);
+        assert_eq!(clip(&ops, 50, (0, 40)), None);
+    }
+
+    #[test]
+    fn test_trim() {
+        let sequences = Sequences::default();
+
+        assert_eq!(
+            trim(TEST_PAF_STRING, (60, 74), &sequences).unwrap(),
+            "qry\t100\t22\t36\t+\ttgt\t200\t60\t74\t13\t14\t60\ttp:A:P\tNM:i:1\tcg:Z:8=1X5="
+        );
+
+        // query positions count from the end on the reverse strand
+        let reverse = TEST_PAF_STRING.replace("\t+\t", "\t-\t");
+        assert_eq!(
+            trim(&reverse[..], (60, 74), &sequences).unwrap(),
+            "qry\t100\t14\t28\t-\ttgt\t200\t60\t74\t13\t14\t60\ttp:A:P\tNM:i:1\tcg:Z:8=1X5="
+        );
+
+        assert_eq!(
+            trim(TEST_PAF_STRING, (0, 10), &sequences).unwrap(),
+            TEST_PAF_STRING
+        );
+
+        // columns separated by spaces are written back separated by tabs
+        let spaces = TEST_PAF_STRING.replace('\t', " ");
+        assert_eq!(
+            trim(&spaces[..], (60, 74), &sequences).unwrap(),
+            "qry\t100\t22\t36\t+\ttgt\t200\t60\t74\t13\t14\t60\ttp:A:P\tNM:i:1\tcg:Z:8=1X5="
+        );
+    }
+
+    #[test]
+    fn test_trim_resolves_matches() {
+        // the reverse complement of the query differs from the target at 5
+        let sequences = fasta::test_sequences(">tgt\nAACCGGTTAC\n>qry\nGTAATCGGTT\n");
+
+        let line = "qry\t10\t0\t10\t-\ttgt\t10\t0\t10\t10\t10\t60\tcg:Z:10M";
+        assert_eq!(
+            trim(line, (2, 8), &sequences).unwrap(),
+            "qry\t10\t2\t8\t-\ttgt\t10\t2\t8\t5\t6\t60\tNM:i:1\tcg:Z:3=1X2="
+        );
+    }
+}
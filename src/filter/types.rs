@@ -1,21 +1,175 @@
-use coitrees::COITree;
+use std::collections::HashMap;
 use std::fmt;
 use std::hash::{Hash, Hasher};
+use std::str::FromStr;
 
 // A Segment is a text, query pair of lo, hi or start, stop of the filter
 #[allow(dead_code)]
 pub type Segment = ((usize, usize), (usize, usize));
 // pub type Length = u32;
 
+// A match run of a PAF record on a single diagonal
 #[derive(Clone)]
 pub struct PafMetadata {
-    pub name: String,
     pub line_num: u32, // the line of the alignment in the PAF file
+
+    // the target positions target_start..target_stop covered by the run
+    pub target_start: u32,
+    pub target_stop: u32,
+}
+
+// The rectangle a PAF record spans, query positions are in the same
+// coordinates as the match runs of its PairIndex
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Block {
+    pub line_num: u32,
+    pub target_start: u32,
+    pub target_stop: u32,
+    pub query_start: u32,
+    pub query_stop: u32,
+    pub matches: u32, // bases in the match runs of the record
+}
+
+// A compact stand-in for a sequence name, see Names
+pub type SequenceId = u32;
+
+// Symbol table holding each sequence name exactly once
+#[derive(Default)]
+pub struct Names {
+    ids: HashMap<String, SequenceId>,
+    names: Vec<String>,
+}
+
+impl Names {
+    // get the id of a name adding the name if we haven't seen it before
+    pub fn intern(&mut self, name: &str) -> SequenceId {
+        if let Some(id) = self.ids.get(name) {
+            return *id;
+        }
+
+        let id = self.names.len() as SequenceId;
+        self.names.push(String::from(name));
+        self.ids.insert(String::from(name), id);
+
+        id
+    }
+
+    pub fn get_id(&self, name: &str) -> Option<SequenceId> {
+        self.ids.get(name).copied()
+    }
+
+    pub fn len(&self) -> usize {
+        self.names.len()
+    }
+}
+
+// The query, target pair and orientation in which a PAF record aligns
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq)]
+pub struct PairKey {
+    pub query: SequenceId,
+    pub target: SequenceId,
+    pub strand: Strand,
+}
+
+// The match runs of a single query, target pair indexed on their diagonal.
+// A cell (v, h) is on diagonal h - v so a run of matches stays on one diagonal
+// and only differs from other runs on it by its offset along the diagonal.
+// Query positions of reverse strand pairs are in reverse complement coordinates.
+//
+// Each diagonal holds the runs of every record on it, one Vec per record. The
+// runs of a record don't overlap and come in the order of the alignment, so a
+// Vec is sorted by target_start and target_stop alike and a lookup is a binary
+// search per record rather than a scan of the diagonal.
+#[derive(Default)]
+pub struct PairIndex {
+    pub diagonals: HashMap<i32, Vec<Vec<PafMetadata>>>,
+    pub blocks: Vec<Block>,
+}
+
+impl PairIndex {
+    fn diagonal(v: u32, h: u32) -> i32 {
+        h as i32 - v as i32
+    }
+
+    // add a match run on a diagonal, a record has to add its runs one after the
+    // other and in the order of their target_start
+    pub fn add_run(&mut self, diagonal: i32, run: PafMetadata) {
+        let records = self.diagonals.entry(diagonal).or_default();
+        match records.last_mut() {
+            Some(runs) if runs[0].line_num == run.line_num => runs.push(run),
+            _ => records.push(vec![run]),
+        }
+    }
+
+    // the number of match runs in the index
+    #[cfg(test)]
+    pub fn run_count(&self) -> usize {
+        self.diagonals.values().flatten().map(Vec::len).sum()
+    }
+
+    // visit the match runs that contain the cell (v, h)
+    pub fn query_cell<F>(&self, v: u32, h: u32, mut visit: F)
+    where
+        F: FnMut(&PafMetadata),
+    {
+        let records = match self.diagonals.get(&Self::diagonal(v, h)) {
+            Some(r) => r,
+            None => return,
+        };
+
+        for runs in records {
+            // the last run starting at or before h is the only one that can hold it
+            let i = runs.partition_point(|run| run.target_start <= h);
+            if i > 0 && h < runs[i - 1].target_stop {
+                visit(&runs[i - 1]);
+            }
+        }
+    }
+
+    // visit the match runs that share cells with the diagonal run of length cells
+    // starting at (v, h) along with the target positions they share
+    pub fn query_run<F>(&self, v: u32, h: u32, length: u32, mut visit: F)
+    where
+        F: FnMut(&PafMetadata, u32, u32),
+    {
+        let records = match self.diagonals.get(&Self::diagonal(v, h)) {
+            Some(r) => r,
+            None => return,
+        };
+        let stop = h + length;
+
+        for runs in records {
+            // skip the runs that end before h, the rest overlap until one starts at stop
+            let first = runs.partition_point(|run| run.target_stop <= h);
+            for run in runs[first..]
+                .iter()
+                .take_while(|run| run.target_start < stop)
+            {
+                let overlap_start = std::cmp::max(h, run.target_start);
+                let overlap_stop = std::cmp::min(stop, run.target_stop);
+                if overlap_start < overlap_stop {
+                    visit(run, overlap_start, overlap_stop);
+                }
+            }
+        }
+    }
 }
 
 pub struct Index {
-    pub target_index: COITree<PafMetadata, u32>,
-    pub query_index: COITree<PafMetadata, u32>,
+    pub names: Names,
+    pub pairs: HashMap<PairKey, PairIndex>,
+}
+
+impl Index {
+    pub fn get(&self, query_name: &str, target_name: &str, strand: Strand) -> Option<&PairIndex> {
+        let key = PairKey {
+            query: self.names.get_id(query_name)?,
+            target: self.names.get_id(target_name)?,
+            strand,
+        };
+
+        self.pairs.get(&key)
+    }
 }
 
 #[derive(Debug, Hash, PartialEq, Eq)]
@@ -27,6 +181,8 @@ pub struct MatchRegion {
     pub text_stop: usize,
 }
 
+// Where a traceback run of a segment shares cells with a match run of a PAF
+// record, each overlap is kept as it makes up the span --trim clips to
 #[derive(Debug, Copy, Clone)]
 pub struct QueryResult {
     pub line: u32, // PAF record ID the line in the PAF file from which we got this result
@@ -44,6 +200,8 @@ pub struct QueryResult {
 impl PartialEq for QueryResult {
     fn eq(&self, other: &Self) -> bool {
         self.line == other.line
+            && self.sequence_start == other.sequence_start
+            && self.sequence_stop == other.sequence_stop
             && self.segment_tstart == other.segment_tstart
             && self.segment_tstop == other.segment_tstop
             && self.segment_qstart == other.segment_qstart
@@ -56,6 +214,8 @@ impl Eq for QueryResult {}
 impl Hash for QueryResult {
     fn hash<H: Hasher>(&self, state: &mut H) {
         self.line.hash(state);
+        self.sequence_start.hash(state);
+        self.sequence_stop.hash(state);
         self.segment_tstart.hash(state);
         self.segment_tstop.hash(state);
         self.segment_qstart.hash(state);
@@ -88,18 +248,20 @@ impl fmt::Display for QueryResult {
     }
 }
 
-#[derive(PartialEq, Clone, Copy, Debug)]
+#[derive(PartialEq, Eq, Hash, Clone, Copy, Debug)]
 pub enum Strand {
     Forward,
     Reverse,
 }
 
-impl Strand {
-    pub fn from_char(c: char) -> Strand {
-        if '+' == c {
-            Strand::Forward
-        } else {
-            Strand::Reverse
+impl FromStr for Strand {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "+" => Ok(Strand::Forward),
+            "-" => Ok(Strand::Reverse),
+            _ => Err(()),
         }
     }
 }
@@ -111,21 +273,10 @@ impl fmt::Display for Strand {
     }
 }
 
-// start, stop, line number, name
+// A run of matches starting at query_start, target_start
 #[derive(PartialEq, Debug)]
-pub struct Interval(pub u32, pub u32, pub usize, pub String);
-
-#[derive(PartialEq, Clone, Copy)]
-pub enum SequenceType {
-    Target,
-    Query,
-}
-
-impl fmt::Display for SequenceType {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match *self {
-            SequenceType::Target => write!(f, "Target"),
-            SequenceType::Query => write!(f, "Query"),
-        }
-    }
+pub struct MatchRun {
+    pub query_start: u32,
+    pub target_start: u32,
+    pub length: u32,
 }
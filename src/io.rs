@@ -1,19 +1,399 @@
+use flate2::bufread::MultiGzDecoder;
+use flate2::write::DeflateEncoder;
+use flate2::{Compression, Crc};
 use std::fs;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
-pub fn read_file(fp: &str) -> Vec<String> {
-    let paf_byte_vector: Vec<u8> = fs::read(fp).expect("Error reading file");
-    let x: String = String::from_utf8_lossy(&paf_byte_vector)
-        .parse()
-        .expect("Error decoding file. Does it contain non ASCII characters?");
+use crate::error::{Error, Result};
 
-    x.lines().map(|x| x.to_string()).collect::<Vec<String>>()
+// gzip and therefore BGZF files start with these bytes
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+// Wrap reader in a gzip decoder if it starts with the gzip magic bytes.
+// MultiGzDecoder reads all members of the file so BGZF works as well.
+fn decode<R: Read + 'static>(reader: R) -> io::Result<Box<dyn BufRead>> {
+    let mut reader = BufReader::new(reader);
+    let compressed = reader.fill_buf()?.starts_with(&GZIP_MAGIC);
+
+    if compressed {
+        Ok(Box::new(BufReader::new(MultiGzDecoder::new(reader))))
+    } else {
+        Ok(Box::new(reader))
+    }
+}
+
+pub fn is_compressed(fp: &str) -> Result<bool> {
+    let mut magic: Vec<u8> = Vec::new();
+    fs::File::open(fp)
+        .and_then(|file| file.take(GZIP_MAGIC.len() as u64).read_to_end(&mut magic))
+        .map_err(|e| Error::io(fp, e))?;
+
+    Ok(magic == GZIP_MAGIC)
+}
+
+// The file name we take to mean stdin
+pub const STDIN: &str = "-";
+
+// Open a plain text, gzip or BGZF compressed file or stdin for reading
+pub fn open_input(fp: &str) -> Result<Box<dyn BufRead>> {
+    if fp == STDIN {
+        decode(io::stdin()).map_err(|e| Error::io(fp, e))
+    } else {
+        open_file(fp)
+    }
+}
+
+// Open a plain text, gzip or BGZF compressed file for reading
+pub fn open_file(fp: &str) -> Result<Box<dyn BufRead>> {
+    fs::File::open(fp)
+        .and_then(decode)
+        .map_err(|e| Error::io(fp, e))
+}
+
+// A copy of input that can't be read a second time e.g. stdin in a temporary
+// file so that records can be copied from their offsets as from any other
+// file. The file is removed when the spool is dropped.
+#[derive(Debug)]
+pub struct Spool {
+    path: PathBuf,
+    writer: BufWriter<fs::File>,
+}
+
+impl Spool {
+    pub fn new() -> io::Result<Self> {
+        // a name of its own for every spool of the process
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "wffilter-spool-{}-{}.paf",
+            std::process::id(),
+            COUNT.fetch_add(1, Ordering::Relaxed)
+        ));
+        let writer = BufWriter::new(fs::File::create(&path)?);
+
+        Ok(Spool { path, writer })
+    }
+
+    pub fn path(&self) -> &str {
+        self.path.to_str().unwrap_or_default()
+    }
+
+    pub fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.writer.write_all(buf)
+    }
+
+    // make everything written so far readable from path
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+impl Drop for Spool {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+// Turns a record, given its number and its line without the newline, into the
+// line to write instead
+pub type Rewrite<'a> = dyn Fn(usize, &str) -> Result<String> + 'a;
+
+// Copy the given records to writer from the offsets recorded while parsing.
+// Expects records to be sorted so that we only ever move forward, skip moves
+// the reader forward by the given number of bytes.
+// fp and output name the two ends in errors.
+fn write_records<R, W, S>(
+    mut reader: R,
+    offsets: &[u64],
+    records: &[usize],
+    writer: &mut W,
+    mut skip: S,
+    rewrite: Option<&Rewrite>,
+    (fp, output): (&str, &str),
+) -> Result<()>
+where
+    R: BufRead,
+    W: Write,
+    S: FnMut(&mut R, u64) -> io::Result<()>,
+{
+    let mut position: u64 = 0;
+    let mut line: Vec<u8> = Vec::new();
+
+    for record in records {
+        let offset = offsets[*record];
+        skip(&mut reader, offset - position).map_err(|e| Error::io(fp, e))?;
+
+        line.clear();
+        let bytes_read = reader
+            .read_until(b'\n', &mut line)
+            .map_err(|e| Error::io(fp, e))?;
+        position = offset + bytes_read as u64;
+
+        if let Some(rewrite) = rewrite {
+            let text = String::from_utf8_lossy(&line).into_owned();
+            line = rewrite(*record, text.trim_end_matches(&['\n', '\r'][..]))?.into_bytes();
+        }
+
+        if !line.ends_with(b"\n") {
+            line.push(b'\n');
+        }
+        writer.write_all(&line).map_err(|e| Error::io(output, e))?;
+    }
+
+    writer.flush().map_err(|e| Error::io(output, e))
+}
+
+fn copy_records<W: Write>(
+    fp: &str,
+    offsets: &[u64],
+    filtered: &[usize],
+    writer: &mut W,
+    rewrite: Option<&Rewrite>,
+    output: &str,
+) -> Result<()> {
+    if is_compressed(fp)? {
+        // we can't seek in a compressed stream so decompress and discard instead
+        let skip = |reader: &mut Box<dyn BufRead>, n: u64| {
+            io::copy(&mut reader.take(n), &mut io::sink()).map(|_| ())
+        };
+        write_records(
+            open_file(fp)?,
+            offsets,
+            filtered,
+            writer,
+            skip,
+            rewrite,
+            (fp, output),
+        )
+    } else {
+        // a relative seek keeps what is already buffered when records are close
+        let skip = |reader: &mut BufReader<fs::File>, n: u64| reader.seek_relative(n as i64);
+        let file = fs::File::open(fp).map_err(|e| Error::io(fp, e))?;
+        write_records(
+            BufReader::new(file),
+            offsets,
+            filtered,
+            writer,
+            skip,
+            rewrite,
+            (fp, output),
+        )
+    }
+}
+
+// Write the filtered records to output or stdout if it's None.
+// Records are read from the input file fp, the spool for stdin, and passed
+// through rewrite if given.
+pub fn copy_filtered(
+    fp: &str,
+    offsets: &[u64],
+    filtered: &[usize],
+    rewrite: Option<&Rewrite>,
+    output: Option<&str>,
+    bgzip: bool,
+) -> Result<()> {
+    let output_name = output.unwrap_or("stdout");
+    let writer: Box<dyn Write> = match output {
+        Some(output_fp) => {
+            Box::new(fs::File::create(output_fp).map_err(|e| Error::io(output_fp, e))?)
+        }
+        None => Box::new(io::stdout()),
+    };
+    let writer = BufWriter::new(writer);
+
+    if bgzip {
+        let mut writer = BgzfWriter::new(writer);
+        copy_records(fp, offsets, filtered, &mut writer, rewrite, output_name)?;
+        writer.finish().map_err(|e| Error::io(output_name, e))?;
+    } else {
+        let mut writer = writer;
+        copy_records(fp, offsets, filtered, &mut writer, rewrite, output_name)?;
+    }
+
+    Ok(())
+}
+
+// BGZF as described in the SAM spec: a series of gzip members each holding at
+// most 64KiB of input with the size of the compressed block in an extra field
+// and an empty block marking the end of the file.
+const BGZF_BLOCK_SIZE: usize = 0xff00;
+const BGZF_EOF: [u8; 28] = [
+    0x1f, 0x8b, 0x08, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0xff, 0x06, 0x00, 0x42, 0x43, 0x02, 0x00,
+    0x1b, 0x00, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+];
+
+pub struct BgzfWriter<W: Write> {
+    inner: W,
+    buffer: Vec<u8>,
+}
+
+impl<W: Write> BgzfWriter<W> {
+    pub fn new(inner: W) -> Self {
+        BgzfWriter {
+            inner,
+            buffer: Vec::with_capacity(BGZF_BLOCK_SIZE),
+        }
+    }
+
+    fn write_block(&mut self) -> io::Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+
+        let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&self.buffer)?;
+        let compressed = encoder.finish()?;
+
+        let mut crc = Crc::new();
+        crc.update(&self.buffer);
+
+        // header (18 bytes) + compressed data + crc32 (4 bytes) + input size (4 bytes)
+        let block_size = 18 + compressed.len() + 8;
+        let bsize = (block_size - 1) as u16;
+
+        let header: [u8; 18] = [
+            0x1f,
+            0x8b,
+            0x08,
+            0x04,
+            0x00,
+            0x00,
+            0x00,
+            0x00,
+            0x00,
+            0xff,
+            0x06,
+            0x00,
+            b'B',
+            b'C',
+            0x02,
+            0x00,
+            bsize as u8,
+            (bsize >> 8) as u8,
+        ];
+        self.inner.write_all(&header)?;
+        self.inner.write_all(&compressed)?;
+        self.inner.write_all(&crc.sum().to_le_bytes())?;
+        self.inner
+            .write_all(&(self.buffer.len() as u32).to_le_bytes())?;
+
+        self.buffer.clear();
+        Ok(())
+    }
+
+    // write out what is left and the end of file marker
+    pub fn finish(mut self) -> io::Result<W> {
+        self.write_block()?;
+        self.inner.write_all(&BGZF_EOF)?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
 }
 
-#[allow(dead_code)]
-pub fn copy_filtered(fp: &str, filtered: &Vec<usize>) {
-    let data = read_file(fp);
+impl<W: Write> Write for BgzfWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = std::cmp::min(buf.len(), BGZF_BLOCK_SIZE - self.buffer.len());
+        self.buffer.extend_from_slice(&buf[..n]);
+        if self.buffer.len() == BGZF_BLOCK_SIZE {
+            self.write_block()?;
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.write_block()?;
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static TEST_DATA: &str = "a\tline\nanother\tline\n\nlast\tline";
+
+    fn bgzip(data: &[u8]) -> Vec<u8> {
+        let mut writer = BgzfWriter::new(Vec::new());
+        writer.write_all(data).unwrap();
+        writer.finish().unwrap()
+    }
+
+    #[test]
+    fn test_write_records() {
+        let offsets: Vec<u64> = vec![0, 7, 21];
+        let mut output: Vec<u8> = Vec::new();
+        let skip = |reader: &mut &[u8], n: u64| {
+            reader.consume(n as usize);
+            Ok(())
+        };
+
+        write_records(
+            TEST_DATA.as_bytes(),
+            &offsets,
+            &[0, 2],
+            &mut output,
+            skip,
+            None,
+            ("in", "out"),
+        )
+        .unwrap();
+
+        assert_eq!(String::from_utf8(output).unwrap(), "a\tline\nlast\tline\n");
+
+        let mut output: Vec<u8> = Vec::new();
+        let rewrite = |record: usize, line: &str| Ok(format!("{}\t{}", record, line));
+        write_records(
+            TEST_DATA.as_bytes(),
+            &offsets,
+            &[1],
+            &mut output,
+            skip,
+            Some(&rewrite),
+            ("in", "out"),
+        )
+        .unwrap();
+
+        assert_eq!(String::from_utf8(output).unwrap(), "1\tanother\tline\n");
+    }
+
+    #[test]
+    fn test_spool() {
+        let mut spool = Spool::new().unwrap();
+        spool.write_all(TEST_DATA.as_bytes()).unwrap();
+        spool.flush().unwrap();
+
+        let path = String::from(spool.path());
+        assert_eq!(fs::read_to_string(&path).unwrap(), TEST_DATA);
+
+        drop(spool);
+        assert!(fs::metadata(&path).is_err());
+    }
+
+    #[test]
+    fn test_bgzf_round_trip() {
+        // large enough to span several blocks
+        let data: Vec<u8> = TEST_DATA.repeat(10_000).into_bytes();
+        let compressed = bgzip(&data);
+
+        assert!(compressed.starts_with(&GZIP_MAGIC));
+        assert!(compressed.ends_with(&BGZF_EOF));
+
+        let mut decompressed: Vec<u8> = Vec::new();
+        decode(io::Cursor::new(compressed))
+            .unwrap()
+            .read_to_end(&mut decompressed)
+            .unwrap();
+        assert_eq!(data, decompressed);
+    }
 
-    filtered.iter().for_each(|line| {
-        println!("{}", data.get(*line).unwrap());
-    });
+    #[test]
+    fn test_decode_plain() {
+        let mut decoded = String::new();
+        decode(io::Cursor::new(TEST_DATA))
+            .unwrap()
+            .read_to_string(&mut decoded)
+            .unwrap();
+        assert_eq!(TEST_DATA, decoded);
+    }
 }
@@ -1,13 +1,24 @@
 mod cli;
+mod error;
+mod fasta;
 mod filter;
 mod io;
 mod mashmap;
 mod paf;
+mod report;
 mod types;
 
+use std::process;
 use std::time::Instant;
 
 fn main() {
+    if let Err(e) = run() {
+        eprintln!("[wffilter::main] error: {}", e);
+        process::exit(1);
+    }
+}
+
+fn run() -> error::Result<()> {
     let total_time = Instant::now();
 
     // -----------------
@@ -48,7 +59,10 @@ fn main() {
             );
         }
 
-        mashmap_mappings = Some(mashmap::MashMapOutput::from_file(mashmap_file_path));
+        mashmap_mappings = Some(mashmap::MashMapOutput::from_file(
+            mashmap_file_path,
+            config.skip_invalid,
+        )?);
         if verbosity > 1 {
             eprintln!(
                 "[wffilter::main] done parsing mashmap output. Time taken {} seconds.",
@@ -58,40 +72,53 @@ fn main() {
     }
 
     // ------------
-    //     PAF
+    //     FASTA
     // ------------
 
-    // Parse the PAF input file
-    let now = Instant::now();
-    if verbosity > 0 {
-        eprintln!("[wffilter::main] parsing PAF: {}", paf_file_path);
-    }
-
-    let paf = paf::PAF::from_file(paf_file_path);
-    if verbosity > 1 {
+    // Sequences are only read from disk once they are needed
+    if verbosity > 0 && !config.fasta.is_empty() {
         eprintln!(
-            "[wffilter::main] done parsing PAF. Time taken {} seconds.",
-            now.elapsed().as_millis() as f64 / 1000.0
-        )
+            "[wffilter::main] indexing FASTA: {}",
+            config.fasta.join(", ")
+        );
     }
+    let sequences = fasta::Sequences::from_files(&config.fasta)?;
 
-    // ------------
-    //     Index
-    // ------------
+    // -----------------
+    //     PAF & Index
+    // -----------------
 
+    // Stream the PAF input file indexing each record as it's parsed
     let now = Instant::now();
     if verbosity > 0 {
-        eprintln!("[wffilter::main] indexing");
+        eprintln!(
+            "[wffilter::main] parsing and indexing PAF: {}",
+            paf_file_path
+        );
     }
 
-    // Generate the index
-    let index: filter::types::Index = filter::index::index_paf_matches(&paf);
+    let mut report = report::Report::default();
+    let (paf, index): (paf::PAF, filter::types::Index) = filter::index::index_paf_matches(
+        io::open_input(paf_file_path)?,
+        &config,
+        &sequences,
+        &mut report,
+    )?;
 
     if verbosity > 1 {
         eprintln!(
-            "[wffilter::main] done indexing. Time taken {} seconds.",
+            "[wffilter::main] done indexing {} sequences. Time taken {} seconds.",
+            index.names.len(),
             now.elapsed().as_millis() as f64 / 1000.0
-        )
+        );
+        eprintln!(
+            "[wffilter::main] {} records were approximated from their coordinates",
+            report.count(report::Status::Approximate)
+        );
+        eprintln!(
+            "[wffilter::main] {} records were removed by the prefilter",
+            report.count_removed()
+        );
     }
 
     // ------------
@@ -104,19 +131,30 @@ fn main() {
     }
 
     // todo: pass this to fns
-    let mut filtered_lines: Vec<usize>;
+    let mut filtered: filter::filter::Filtered;
 
     if config.mashmap_filepath.is_some() {
-        filtered_lines = filter::filter::filter_mashmap(mashmap_mappings.as_ref(), &index, &paf, &config);
+        filtered = filter::filter::filter_mashmap(
+            mashmap_mappings.as_ref(),
+            &index,
+            &paf,
+            &sequences,
+            &config,
+        )?;
     } else {
-        filtered_lines = filter::filter::filter(&index, &paf, &config);
+        filtered = filter::filter::filter(&index, &paf, &sequences, &config)?;
     }
 
     if verbosity > 1 {
         eprintln!(
             "[wffilter::main] done filtering. Time taken {} seconds.",
             now.elapsed().as_millis() as f64 / 1000.0
-        )
+        );
+        eprintln!(
+            "[wffilter::main] kept {} of {} indexed records",
+            filtered.lines.len(),
+            filtered.support.len()
+        );
     }
 
     // --------------------------
@@ -128,7 +166,22 @@ fn main() {
         eprintln!("[wffilter::main] copying filtered lines");
     }
 
-    io::copy_filtered(paf_file_path, &filtered_lines);
+    let trim = |record: usize, line: &str| -> error::Result<String> {
+        match filtered.spans.get(&record) {
+            Some(span) => filter::trim::trim(line, *span, &sequences)
+                .map_err(|e| error::Error::record(paf_file_path, record, e)),
+            None => Ok(String::from(line)),
+        }
+    };
+
+    io::copy_filtered(
+        paf.get_spooled().unwrap_or(paf_file_path),
+        paf.get_offsets(),
+        &filtered.lines,
+        if config.trim { Some(&trim) } else { None },
+        config.output.as_deref(),
+        config.bgzip,
+    )?;
 
     if verbosity > 1 {
         eprintln!(
@@ -137,10 +190,29 @@ fn main() {
         )
     }
 
+    if let Some(support_file_path) = config.support_output.as_ref() {
+        filter::support::to_file(
+            &filtered.support,
+            &config.min_support,
+            paf.get_line_numbers(),
+            support_file_path,
+        )?;
+    }
+
+    if let Some(alignment_file_path) = config.alignment_output.as_ref() {
+        filter::alignment::to_file(&filtered.alignments, alignment_file_path)?;
+    }
+
+    if let Some(report_file_path) = config.report.as_ref() {
+        report.to_file(paf.get_line_numbers(), report_file_path)?;
+    }
+
     if verbosity > 1 {
         eprintln!(
             "[wffilter::main] all done. Total time taken {} seconds.",
             total_time.elapsed().as_millis() as f64 / 1000.0
         )
     }
+
+    Ok(())
 }
@@ -1,11 +1,19 @@
+use crate::error::{parse_column, Error, FieldError, Result};
 use crate::filter::types;
 use crate::io;
-use std::str::FromStr;
+use crate::paf;
 use std::collections::HashMap;
 use std::collections::HashSet;
+use std::io::BufRead;
 
+/*
+A mapping from MashMap or wfmash used to guide the global alignment.
 
-
+MashMap writes 10 tab separated columns, the first 9 as in PAF but with
+inclusive end positions, followed by the estimated identity as a percentage.
+wfmash writes PAF with the estimated identity in an id:f tag. Either way ends
+are stored as exclusive and the identity as a fraction.
+*/
 #[derive(Debug, PartialEq)]
 pub struct MashMapLine {
     pub query: String,
@@ -19,31 +27,113 @@ pub struct MashMapLine {
     pub target_length: u32,
     pub target_start: u32,
     pub target_stop: u32,
+
+    pub identity: f64, // estimated identity 0..1
 }
 
 impl MashMapLine {
-    pub fn from_lines(lines: Vec<String>) -> Vec<MashMapLine> {
-        lines
-            .iter()
-            .map(|line| Self::from_str(&line[..]))
-            .collect::<Vec<Self>>()
+    // Parse every line of reader, file_name is only used in errors.
+    // With skip_invalid bad lines are logged and dropped instead of returned.
+    pub fn from_reader<R: BufRead>(
+        reader: R,
+        file_name: &str,
+        skip_invalid: bool,
+    ) -> Result<Vec<Self>> {
+        let mut mappings: Vec<Self> = Vec::new();
+
+        for (index, line) in reader.lines().enumerate() {
+            let line = line.map_err(|e| Error::io(file_name, e))?;
+            if line.is_empty() {
+                continue;
+            }
+
+            match Self::from_str(&line[..]) {
+                Ok(mapping) => mappings.push(mapping),
+                Err(e) => {
+                    let e = Error::parse(file_name, index + 1, e);
+                    if !skip_invalid {
+                        return Err(e);
+                    }
+                    eprintln!(
+                        "[wffilter::mashmap::MashMapLine::from_reader] skipping {}",
+                        e
+                    );
+                }
+            }
+        }
+
+        Ok(mappings)
     }
 
-    pub fn from_str(line: &str) -> Self {
+    pub fn from_str(line: &str) -> Result<Self, FieldError> {
         let it: Vec<&str> = line.split_whitespace().collect();
-
-        //need a more robust way to index into the vector
-        Self {
-            query: it[0].to_string(),
-            query_length: u32::from_str(it[1]).unwrap(),
-            query_start: u32::from_str(it[2]).unwrap(),
-            query_stop: u32::from_str(it[3]).unwrap(),
-            strand: types::Strand::from_char(char::from_str(it[4]).unwrap()),
-            target: it[5].to_string(),
-            target_length: u32::from_str(it[6]).unwrap(),
-            target_start: u32::from_str(it[7]).unwrap(),
-            target_stop: u32::from_str(it[8]).unwrap(),
+        if it.len() >= 12 {
+            return Self::from_paf(line);
         }
+
+        let inclusive_end = |index: usize, name: &str| -> Result<u32, FieldError> {
+            let end = parse_column::<u32>(&it, index, name)?;
+            end.checked_add(1)
+                .ok_or_else(|| FieldError::new(name, &format!("{} is too large", end)))
+        };
+
+        let mapping = Self {
+            query: parse_column(&it, 0, "query name")?,
+            query_length: parse_column(&it, 1, "query length")?,
+            query_start: parse_column(&it, 2, "query start")?,
+            query_stop: inclusive_end(3, "query end")?,
+            strand: parse_column(&it, 4, "strand")?,
+            target: parse_column(&it, 5, "target name")?,
+            target_length: parse_column(&it, 6, "target length")?,
+            target_start: parse_column(&it, 7, "target start")?,
+            target_stop: inclusive_end(8, "target end")?,
+            identity: parse_column::<f64>(&it, 9, "identity")? / 100.0,
+        };
+
+        paf::check_range(
+            "query",
+            mapping.query_start,
+            mapping.query_stop,
+            mapping.query_length,
+        )?;
+        paf::check_range(
+            "target",
+            mapping.target_start,
+            mapping.target_stop,
+            mapping.target_length,
+        )?;
+
+        Ok(mapping)
+    }
+
+    // A wfmash mapping. Older wfmash versions report id:f as a percentage.
+    // Without id:f we fall back to residue matches over block length.
+    fn from_paf(line: &str) -> Result<Self, FieldError> {
+        let a = paf::PafAlignment::from_str(line)?;
+        let identity = match a.tag_float("id") {
+            Some(id) if id > 1.0 => id / 100.0,
+            Some(id) => id,
+            None if a.block_length > 0 => a.residue_matches as f64 / a.block_length as f64,
+            None => {
+                return Err(FieldError::new(
+                    "id",
+                    "missing id:f and the block length is 0",
+                ))
+            }
+        };
+
+        Ok(Self {
+            query: a.query,
+            query_length: a.query_length,
+            query_start: a.query_start,
+            query_stop: a.query_end,
+            strand: a.strand,
+            target: a.target,
+            target_length: a.target_length,
+            target_start: a.target_start,
+            target_stop: a.target_end,
+            identity,
+        })
     }
 }
 
@@ -60,6 +150,8 @@ pub struct AlignmentPair {
 
 #[derive(Debug, PartialEq, Eq, Hash)]
 pub struct AlignmentBounds {
+    pub strand: types::Strand,
+
     pub query_length: u32,
     pub query_start: u32,
     pub query_stop: u32,
@@ -69,26 +161,48 @@ pub struct AlignmentBounds {
     pub target_stop: u32,
 }
 
+impl AlignmentBounds {
+    pub fn target_range(&self) -> (usize, usize) {
+        (self.target_start as usize, self.target_stop as usize)
+    }
+
+    // The mapped query range in the reverse complement coordinates the index
+    // uses for reverse strand mappings
+    pub fn query_range(&self) -> (usize, usize) {
+        match self.strand {
+            types::Strand::Forward => (self.query_start as usize, self.query_stop as usize),
+            types::Strand::Reverse => (
+                (self.query_length - self.query_stop) as usize,
+                (self.query_length - self.query_start) as usize,
+            ),
+        }
+    }
+}
+
 impl MashMapOutput {
-    pub fn from_file(file_name: &str) -> Self {
-        let lines: Vec<String> = io::read_file(&file_name[..]);
-        let mappings: Vec<MashMapLine> = MashMapLine::from_lines(lines);
+    pub fn from_file(file_name: &str, skip_invalid: bool) -> Result<Self> {
+        let mappings =
+            MashMapLine::from_reader(io::open_file(file_name)?, file_name, skip_invalid)?;
 
-        Self { mappings }
+        Ok(Self { mappings })
     }
 
-    // Go through all the mappings and get the start and stop positions of each
-    // query and target pair
-    pub fn gen_unique_mappings(&self) -> HashMap<AlignmentPair, HashSet<AlignmentBounds>> {
+    // Go through all the mappings with at least min_identity and get the start
+    // and stop positions of each query and target pair
+    pub fn gen_unique_mappings(
+        &self,
+        min_identity: f64,
+    ) -> HashMap<AlignmentPair, HashSet<AlignmentBounds>> {
         let mut unique_mappings: HashMap<AlignmentPair, HashSet<AlignmentBounds>> = HashMap::new();
 
-        for mapping in &self.mappings {
+        for mapping in self.mappings.iter().filter(|m| m.identity >= min_identity) {
             let query_target_pair = AlignmentPair {
                 query: mapping.query.clone(),
                 target: mapping.target.clone(),
             };
 
             let bounds = AlignmentBounds {
+                strand: mapping.strand,
                 query_length: mapping.query_length,
                 query_start: mapping.query_start,
                 query_stop: mapping.query_stop,
@@ -98,10 +212,12 @@ impl MashMapOutput {
             };
 
             match unique_mappings.get_mut(&query_target_pair) {
-                Some(v) => { // update
+                Some(v) => {
+                    // update
                     v.insert(bounds);
-                },
-                None => { // add new entry
+                }
+                None => {
+                    // add new entry
                     let mut baz: HashSet<AlignmentBounds> = HashSet::new();
                     baz.insert(bounds);
                     unique_mappings.insert(query_target_pair, baz);
@@ -125,23 +241,31 @@ mod tests {
     qry2\t13403\t0\t13402\t+\ttgt2\t13403\t0\t13389\t99.4385\
     \n\
     qry3\t15600\t0\t15599\t-\ttgt3\t15600\t0\t15589\t99.577\
+    \n\
+    qry3\t15600\t100\t1099\t-\ttgt3\t15600\t5000\t5999\t85\
+    ";
+
+    static TEST_WFMASH_STRING: &str = "\
+    qry1\t11068\t0\t11068\t+\ttgt1\t11068\t0\t11049\t11049\t11068\t255\tid:f:0.999938\
     ";
 
     #[test]
     fn test_parse_single_alignment() {
-        let aln = MashMapLine::from_str(TEST_MASHMAP_STRING);
+        let aln = MashMapLine::from_str(TEST_MASHMAP_STRING).unwrap();
         let aln2 = MashMapLine {
             query: String::from("qry1"),
             query_length: 11068,
             query_start: 0,
-            query_stop: 11067,
+            query_stop: 11068,
 
             strand: types::Strand::Forward,
 
             target: String::from("tgt1"),
             target_length: 11068,
             target_start: 0,
-            target_stop: 11048,
+            target_stop: 11049,
+
+            identity: 99.9938 / 100.0,
         };
 
         assert_eq!(aln, aln2);
@@ -149,45 +273,144 @@ mod tests {
 
     #[test]
     fn test_parse_file() {
-        let lines: Vec<String> = TEST_MASHMAP_FILE.lines().map(|x| x.to_string()).collect();
-        let file1 = MashMapLine::from_lines(lines);
+        let file1 = MashMapLine::from_reader(TEST_MASHMAP_FILE.as_bytes(), "", false).unwrap();
 
         let aln2 = MashMapLine {
             query: String::from("qry2"),
             query_length: 13403,
             query_start: 0,
-            query_stop: 13402,
+            query_stop: 13403,
 
             strand: types::Strand::Forward,
 
             target: String::from("tgt2"),
             target_length: 13403,
             target_start: 0,
-            target_stop: 13389,
+            target_stop: 13390,
+
+            identity: 99.4385 / 100.0,
         };
         let aln3 = MashMapLine {
             query: String::from("qry3"),
             query_length: 15600,
             query_start: 0,
-            query_stop: 15599,
+            query_stop: 15600,
 
             strand: types::Strand::Reverse,
 
             target: String::from("tgt3"),
             target_length: 15600,
             target_start: 0,
-            target_stop: 15589,
+            target_stop: 15590,
+
+            identity: 99.577 / 100.0,
         };
-        let file2 = vec![aln2, aln3];
+        let file2 = [aln2, aln3];
+
+        assert_eq!(file1.len(), 3);
+        assert_eq!(file1[..2], file2[..]);
+    }
 
-        assert_eq!(file1, file2);
+    #[test]
+    fn test_parse_invalid_strand() {
+        let mapping = "qry1\t11068\t0\t11067\t*\ttgt1\t11068\t0\t11048\t99.9938";
+        let e = MashMapLine::from_reader(mapping.as_bytes(), "x.map", false).unwrap_err();
+        assert_eq!(
+            format!("{}", e),
+            "x.map:1: invalid strand: could not parse *"
+        );
+
+        let mappings = MashMapLine::from_reader(mapping.as_bytes(), "x.map", true).unwrap();
+        assert!(mappings.is_empty());
+    }
+
+    #[test]
+    fn test_parse_invalid_coordinates() {
+        let inverted = "qry1\t11068\t500\t99\t+\ttgt1\t11068\t0\t11048\t99.9938";
+        let e = MashMapLine::from_reader(inverted.as_bytes(), "x.map", false).unwrap_err();
+        assert_eq!(
+            format!("{}", e),
+            "x.map:1: invalid query start: 500 is after the end 100"
+        );
+
+        // the inclusive end is past the last base
+        let beyond = "qry1\t11068\t0\t11067\t+\ttgt1\t11068\t0\t11068\t99.9938";
+        let e = MashMapLine::from_reader(beyond.as_bytes(), "x.map", false).unwrap_err();
+        assert_eq!(
+            format!("{}", e),
+            "x.map:1: invalid target end: 11069 is beyond the length 11068"
+        );
+
+        let mappings = MashMapLine::from_reader(beyond.as_bytes(), "x.map", true).unwrap();
+        assert!(mappings.is_empty());
+
+        let overflow = "qry1\t11068\t0\t4294967295\t+\ttgt1\t11068\t0\t11048\t99.9938";
+        assert_eq!(
+            MashMapLine::from_str(overflow).unwrap_err(),
+            FieldError::new("query end", "4294967295 is too large")
+        );
+    }
+
+    #[test]
+    fn test_parse_wfmash() {
+        let mapping = MashMapLine::from_str(TEST_WFMASH_STRING).unwrap();
+        let expected = MashMapLine::from_str(TEST_MASHMAP_STRING).unwrap();
+
+        assert_eq!(mapping.query_stop, expected.query_stop);
+        assert_eq!(mapping.target_stop, expected.target_stop);
+        assert!((mapping.identity - expected.identity).abs() < 1e-9);
+
+        // a percentage as written by older wfmash versions
+        let percent = TEST_WFMASH_STRING.replace("id:f:0.999938", "id:f:99.9938");
+        assert_eq!(MashMapLine::from_str(&percent[..]).unwrap(), expected);
+
+        // without id:f
+        let no_id = TEST_WFMASH_STRING.replace("\tid:f:0.999938", "");
+        let mapping = MashMapLine::from_str(&no_id[..]).unwrap();
+        assert_eq!(mapping.identity, 11049.0 / 11068.0);
+    }
+
+    #[test]
+    fn test_bounds_ranges() {
+        let mappings = MashMapLine::from_reader(TEST_MASHMAP_FILE.as_bytes(), "", false).unwrap();
+        let output = MashMapOutput { mappings };
+        let unique_mappings = output.gen_unique_mappings(0.9);
+
+        let pair = AlignmentPair {
+            query: String::from("qry3"),
+            target: String::from("tgt3"),
+        };
+        let bounds: Vec<&AlignmentBounds> = unique_mappings.get(&pair).unwrap().iter().collect();
+
+        assert_eq!(bounds.len(), 1);
+        assert_eq!(bounds[0].target_range(), (0, 15590));
+        assert_eq!(bounds[0].query_range(), (0, 15600));
     }
 
     #[test]
     fn test_gen_unique_mappings() {
-        let lines: Vec<String> = TEST_MASHMAP_FILE.lines().map(|x| x.to_string()).collect();
-        let mappings = MashMapLine::from_lines(lines);
+        let mappings = MashMapLine::from_reader(TEST_MASHMAP_FILE.as_bytes(), "", false).unwrap();
+        let output = MashMapOutput { mappings };
+        let unique_mappings = output.gen_unique_mappings(0.0);
 
+        assert_eq!(unique_mappings.len(), 2);
 
+        let pair = AlignmentPair {
+            query: String::from("qry3"),
+            target: String::from("tgt3"),
+        };
+        let mut ranges: Vec<((usize, usize), (usize, usize))> = unique_mappings
+            .get(&pair)
+            .unwrap()
+            .iter()
+            .map(|b| (b.target_range(), b.query_range()))
+            .collect();
+        ranges.sort();
+
+        // query 100..1100 on the reverse strand of a 15600 long query
+        assert_eq!(
+            ranges,
+            vec![((0, 15590), (0, 15600)), ((5000, 6000), (14500, 15500))]
+        );
     }
 }
@@ -33,28 +33,98 @@ each line.
 use std::collections::HashMap;
 use std::collections::HashSet;
 use std::fmt;
+use std::io::BufRead;
 use std::str;
 use std::str::FromStr;
 
+use crate::error::{parse_column, Error, FieldError, Result};
 use crate::filter::types;
-use crate::io;
+use crate::io::Spool;
+
+/*
+A SAM-like optional field TAG:TYPE:VALUE e.g. NM:i:3, tp:A:P or de:f:0.0012
+
+|Type|Description                      |Tag     |
+|:--:|:--------------------------------|:-------|
+| A  |Printable character              |Char    |
+| i  |Signed integer                   |Int     |
+| f  |Single-precision floating number |Float   |
+| Z  |Printable string                 |String  |
+| H  |Byte array in hex                |Hex     |
+| B  |Integer or numeric array         |Array   |
+
+H and B values are kept as they are e.g. c,1,2,3 for an array of int8.
+*/
+#[derive(Clone, PartialEq, Debug)]
+pub enum Tag {
+    Char(char),
+    Int(i64),
+    Float(f64),
+    String(String),
+    Hex(String),
+    Array(String),
+}
+
+impl FromStr for Tag {
+    type Err = FieldError;
+
+    // parse TYPE:VALUE
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || FieldError::new("tag", &format!("could not parse {}", s));
+        let mut it = s.splitn(2, ':');
+        let (typ, value) = match (it.next(), it.next()) {
+            (Some(t), Some(v)) => (t, v),
+            _ => return Err(invalid()),
+        };
+
+        match typ {
+            "A" if value.chars().count() == 1 => Ok(Tag::Char(value.chars().next().unwrap())),
+            "i" => i64::from_str(value).map(Tag::Int).map_err(|_| invalid()),
+            "f" => f64::from_str(value).map(Tag::Float).map_err(|_| invalid()),
+            "Z" => Ok(Tag::String(String::from(value))),
+            "H" => Ok(Tag::Hex(String::from(value))),
+            "B" => Ok(Tag::Array(String::from(value))),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl fmt::Display for Tag {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Tag::Char(c) => write!(f, "A:{}", c),
+            Tag::Int(i) => write!(f, "i:{}", i),
+            Tag::Float(x) => write!(f, "f:{}", x),
+            Tag::String(z) => write!(f, "Z:{}", z),
+            Tag::Hex(h) => write!(f, "H:{}", h),
+            Tag::Array(b) => write!(f, "B:{}", b),
+        }
+    }
+}
+
+// Optional fields by their two letter tag e.g. "NM"
+pub type Tags = HashMap<String, Tag>;
 
 // A struct over a single line of a PAF file (a single alignment)
 #[derive(PartialEq, Debug)]
 pub struct PafAlignment {
     pub query: String,         // Query sequence name
-    query_length: u32,         // Query sequence length
+    pub query_length: u32,     // Query sequence length
     pub query_start: u32,      // Query start (0-based; BED-like; closed)
     pub query_end: u32,        // Query end (0-based; BED-like; open)
     pub strand: types::Strand, // Relative strand: "+" or "-"
     pub target: String,        // target sequence name
-    target_length: u32,        // Target sequence length
+    pub target_length: u32,    // Target sequence length
     pub target_start: u32,     // Target start on original strand (0-based)
     pub target_end: u32,       // Target end on original strand (0-based)
-    // residue_matches: u32,   // Number of residue matches
-    // block_len: u32,         // Alignment block length
-    // quality: String,        // Mapping quality (0-255; 255 for missing)
-    pub cigar: String, // SAM style CIGAR string TODO: specify CIGAR version
+    pub residue_matches: u32,  // Number of residue matches
+    pub block_length: u32,     // Alignment block length
+    pub mapping_quality: u8,   // Mapping quality (0-255; 255 for missing)
+    // SAM style CIGAR string from cg or converted from cs TODO: specify CIGAR version
+    // None for mapping only records
+    pub cigar: Option<String>,
+    // all other optional fields, cg and cs end up in cigar
+    pub tags: Tags,
 }
 
 // TODO: remove
@@ -73,6 +143,9 @@ impl PafAlignment {
         target_length: u32,
         target_start: u32,
         target_end: u32,
+        residue_matches: u32,
+        block_length: u32,
+        mapping_quality: u8,
         cigar: &str,
     ) -> Self {
         PafAlignment {
@@ -85,54 +158,210 @@ impl PafAlignment {
             target_length,
             target_start,
             target_end,
-            cigar: String::from(cigar),
+            residue_matches,
+            block_length,
+            mapping_quality,
+            cigar: Some(String::from(cigar)),
+            tags: Tags::new(),
         }
     }
-    pub fn from_lines(lines: Vec<String>) -> Vec<PafAlignment> {
-        lines
-            .iter()
-            .map(|line| PafAlignment::from_str(&line[..]))
-            .collect::<Vec<PafAlignment>>()
-    }
 
-    pub fn from_str(line: &str) -> Self {
+    pub fn from_str(line: &str) -> Result<Self, FieldError> {
         let it: Vec<&str> = line.split_whitespace().collect();
-        let sam_fields: &[&str] = &it[9..];
-
-        // expensive
-        // should return an Option<String> in case a field doens't exist
-        let extract_field = |pattern: &str| -> String {
-            let f: &str = sam_fields
-                .iter()
-                .find(|s| s.find(pattern) != None) // if the field is found
-                .unwrap();
-
-            // drop the cg chars
-            String::from(&f[5..])
-        };
+        if it.len() < 12 {
+            return Err(FieldError::new(
+                "record",
+                &format!("expected at least 12 columns found {}", it.len()),
+            ));
+        }
 
-        let extract_strand = || -> types::Strand {
-            if '+' == char::from_str(it[4]).unwrap() {
-                types::Strand::Forward
-            } else {
-                types::Strand::Reverse
+        let mut tags = Tags::new();
+        let mut cg: Option<&str> = None;
+        let mut cs: Option<&str> = None;
+        for field in &it[12..] {
+            // TAG:TYPE:VALUE, only the TAG:TYPE: prefix is required as the
+            // value of a string may be empty e.g. xx:Z:
+            let bytes = field.as_bytes();
+            if bytes.len() < 5 || bytes[2] != b':' || bytes[4] != b':' {
+                return Err(FieldError::new(
+                    "tag",
+                    &format!("could not parse {}", field),
+                ));
             }
+            let (name, value) = (&field[..2], &field[3..]);
+
+            // the first occurrence of a tag wins
+            match name {
+                "cg" => cg = cg.or_else(|| value.strip_prefix("Z:")),
+                "cs" => cs = cs.or_else(|| value.strip_prefix("Z:")),
+                _ if !tags.contains_key(name) => {
+                    let tag =
+                        Tag::from_str(value).map_err(|e| FieldError::new(name, &e.message))?;
+                    tags.insert(String::from(name), tag);
+                }
+                _ => {}
+            }
+        }
+
+        // prefer the CIGAR and fall back to the cs difference string
+        let cigar: Option<String> = match (cg, cs) {
+            (Some(cigar), _) => Some(String::from(cigar)),
+            (None, Some(cs)) => Some(cs_to_cigar(cs)?),
+            (None, None) => None,
         };
 
-        //need a more robust way to index into the vector
-        PafAlignment {
+        let alignment = PafAlignment {
             query: it[0].to_string(),
-            query_length: u32::from_str(it[1]).unwrap(),
-            query_start: u32::from_str(it[2]).unwrap(),
-            query_end: u32::from_str(it[3]).unwrap(),
-            strand: extract_strand(),
+            query_length: parse_column(&it, 1, "query length")?,
+            query_start: parse_column(&it, 2, "query start")?,
+            query_end: parse_column(&it, 3, "query end")?,
+            strand: parse_column(&it, 4, "strand")?,
             target: it[5].to_string(),
-            target_length: u32::from_str(it[6]).unwrap(),
-            target_start: u32::from_str(it[7]).unwrap(),
-            target_end: u32::from_str(it[8]).unwrap(),
-            cigar: extract_field("cg"),
+            target_length: parse_column(&it, 6, "target length")?,
+            target_start: parse_column(&it, 7, "target start")?,
+            target_end: parse_column(&it, 8, "target end")?,
+            residue_matches: parse_column(&it, 9, "residue matches")?,
+            block_length: parse_column(&it, 10, "block length")?,
+            mapping_quality: parse_column(&it, 11, "mapping quality")?,
+            cigar,
+            tags,
+        };
+
+        check_range(
+            "query",
+            alignment.query_start,
+            alignment.query_end,
+            alignment.query_length,
+        )?;
+        check_range(
+            "target",
+            alignment.target_start,
+            alignment.target_end,
+            alignment.target_length,
+        )?;
+
+        Ok(alignment)
+    }
+
+    pub fn tag_int(&self, name: &str) -> Option<i64> {
+        match self.tags.get(name) {
+            Some(Tag::Int(i)) => Some(*i),
+            _ => None,
+        }
+    }
+
+    pub fn tag_float(&self, name: &str) -> Option<f64> {
+        match self.tags.get(name) {
+            Some(Tag::Float(x)) => Some(*x),
+            _ => None,
         }
     }
+
+    pub fn tag_char(&self, name: &str) -> Option<char> {
+        match self.tags.get(name) {
+            Some(Tag::Char(c)) => Some(*c),
+            _ => None,
+        }
+    }
+}
+
+// Coordinates have to satisfy start <= end <= length on either sequence
+pub(crate) fn check_range(
+    sequence: &str,
+    start: u32,
+    end: u32,
+    length: u32,
+) -> Result<(), FieldError> {
+    if start > end {
+        return Err(FieldError::new(
+            &format!("{} start", sequence),
+            &format!("{} is after the end {}", start, end),
+        ));
+    }
+    if end > length {
+        return Err(FieldError::new(
+            &format!("{} end", sequence),
+            &format!("{} is beyond the length {}", end, length),
+        ));
+    }
+
+    Ok(())
+}
+
+/*
+Convert a minimap2 cs difference string in either the short or long form into
+an extended CIGAR string with =, X, I and D operations.
+
+|Op |Regex                     |Description                   |
+|:-:|:------------------------:|:-----------------------------|
+| = |[ACGTN]+                  |Identical sequence (long form)|
+| : |[0-9]+                    |Identical sequence length     |
+| * |[acgtn][acgtn]            |Substitution: ref to query    |
+| + |[acgtn]+                  |Insertion to the reference    |
+| - |[acgtn]+                  |Deletion from the reference   |
+| ~ |[acgtn]{2}[0-9]+[acgtn]{2}|Intron length and splice signal|
+
+Introns skip the reference the same way deletions do so we treat them as such.
+*/
+pub fn cs_to_cigar(cs: &str) -> Result<String, FieldError> {
+    let mut cigar = String::new();
+    let mut last_op: Option<char> = None;
+    let mut last_len: u32 = 0;
+
+    let mut push = |op: char, len: u32| {
+        if last_op == Some(op) {
+            last_len += len;
+            return;
+        }
+        if let Some(o) = last_op {
+            cigar.push_str(&format!("{}{}", last_len, o));
+        }
+        last_op = Some(op);
+        last_len = len;
+    };
+
+    let bytes = cs.as_bytes();
+    let mut i = 0;
+    while i < bytes.len() {
+        let op = bytes[i] as char;
+        i += 1;
+
+        // the operand runs up to the next operator
+        let start = i;
+        while i < bytes.len() && !b":=*+-~".contains(&bytes[i]) {
+            i += 1;
+        }
+        let operand = &cs[start..i];
+
+        let parse_len = |len: &str| -> Result<u32, FieldError> {
+            u32::from_str(len)
+                .map_err(|_| FieldError::new("cs", &format!("could not parse length {}", len)))
+        };
+
+        match op {
+            ':' => push('=', parse_len(operand)?),
+            '=' => push('=', operand.len() as u32),
+            '*' => push('X', operand.len() as u32 / 2),
+            '+' => push('I', operand.len() as u32),
+            '-' => push('D', operand.len() as u32),
+            '~' if operand.len() > 4 => {
+                // drop the splice signals on either side of the length
+                let len = &operand[2..operand.len() - 2];
+                push('D', parse_len(len)?)
+            }
+            _ => {
+                return Err(FieldError::new(
+                    "cs",
+                    &format!("unexpected operation {}{}", op, operand),
+                ))
+            }
+        }
+    }
+    if let Some(o) = last_op {
+        cigar.push_str(&format!("{}{}", last_len, o));
+    }
+
+    Ok(cigar)
 }
 
 impl fmt::Display for PafAlignment {
@@ -143,7 +372,11 @@ impl fmt::Display for PafAlignment {
             .field("strand", &self.strand)
             .field("target start", &self.target_start)
             .field("target end", &self.target_end)
+            .field("residue matches", &self.residue_matches)
+            .field("block length", &self.block_length)
+            .field("mapping quality", &self.mapping_quality)
             .field("cigar", &self.cigar)
+            .field("tags", &self.tags)
             .finish()
     }
 }
@@ -155,93 +388,167 @@ pub struct PafMetadata {
 }
 
 // TODO: should this be part of PAF?
+// A pair with alignments on both strands shows up once per strand
 #[derive(Debug, Hash, PartialEq, Eq)]
 pub struct AlignmentPair {
     pub target_name: String,
     pub query_name: String,
+    pub strand: types::Strand,
 }
 
 type PafLookup = HashMap<String, PafMetadata>;
 
-// A struct over the entire PAF file
+// A summary of the entire PAF file.
+// Records are parsed one line at a time and handed over to a visitor so that
+// we never hold the whole file or all of its alignments in memory. For output
+// we keep only the byte offset at which each record starts.
+// Input that can't be read a second time e.g. stdin is spooled to a temporary file.
 #[derive(Debug)]
 pub struct PAF {
-    alignments: Vec<PafAlignment>,
     metadata: PafLookup,
+    pairs: HashSet<AlignmentPair>,
+    offsets: Vec<u64>, // offset of record n (the nth non empty line) in the file
+    line_numbers: Vec<usize>, // line of record n in the file counting from 1
+    spool: Option<Spool>,
 }
 
 impl PAF {
-    pub fn from_file(file_name: &str) -> PAF {
-        // read PAF file & return a vector of Strings for each line
-        let lines: Vec<String> = io::read_file(&file_name[..]);
+    // Stream a PAF file line by line calling visit with the record number and
+    // alignment of every record. Copy the input to a spool if spool is set.
+    // A record that fails to parse or that visit rejects is an error unless
+    // skip_invalid is set in which case it is logged and dropped.
+    pub fn from_reader<R, F>(
+        mut reader: R,
+        file_name: &str,
+        spool: bool,
+        skip_invalid: bool,
+        mut visit: F,
+    ) -> Result<PAF>
+    where
+        R: BufRead,
+        F: FnMut(usize, &PafAlignment) -> Result<(), FieldError>,
+    {
+        let mut paf = PAF {
+            metadata: HashMap::new(),
+            pairs: HashSet::new(),
+            offsets: Vec::new(),
+            line_numbers: Vec::new(),
+            spool: None,
+        };
+        if spool {
+            paf.spool = Some(Spool::new().map_err(|e| Error::io(file_name, e))?);
+        }
 
-        // Parse each line into a PafAlignment
-        let alignments: Vec<PafAlignment> = PafAlignment::from_lines(lines);
+        let mut buffer: Vec<u8> = Vec::new();
+        let mut offset: u64 = 0;
+        let mut line_number: usize = 0;
+
+        loop {
+            buffer.clear();
+            let bytes_read = reader
+                .read_until(b'\n', &mut buffer)
+                .map_err(|e| Error::io(file_name, e))?;
+            if bytes_read == 0 {
+                break;
+            }
+            line_number += 1;
+
+            let line = String::from_utf8_lossy(&buffer);
+            let line = line.trim_end_matches(&['\n', '\r'][..]);
+            if !line.is_empty() {
+                let record = paf.offsets.len();
+                let parsed = PafAlignment::from_str(line).and_then(|alignment| {
+                    visit(record, &alignment)?;
+                    Ok(alignment)
+                });
+
+                match parsed {
+                    Ok(alignment) => paf.add_alignment(&alignment, offset, line_number),
+                    Err(e) => {
+                        let e = Error::parse(file_name, line_number, e);
+                        if !skip_invalid {
+                            return Err(e);
+                        }
+                        eprintln!("[wffilter::paf::PAF::from_reader] skipping {}", e);
+                    }
+                }
+            }
 
-        let metadata = Self::populate_metadata(&alignments);
+            if let Some(spool) = paf.spool.as_mut() {
+                spool
+                    .write_all(&buffer)
+                    .map_err(|e| Error::io(spool.path(), e))?;
+            }
+
+            offset += bytes_read as u64;
+        }
 
-        PAF {
-            alignments,
-            metadata,
+        if let Some(spool) = paf.spool.as_mut() {
+            spool.flush().map_err(|e| Error::io(spool.path(), e))?;
         }
+
+        Ok(paf)
     }
 
     // A string of alignment lines seperated by newlines
     // TODO: Used in testing. Remove?
     #[allow(dead_code)]
     pub fn from_str(alignment_strings: &str) -> PAF {
-        let alignments: Vec<PafAlignment> = alignment_strings
-            .lines()
-            .map(|l| PafAlignment::from_str(l))
-            .collect();
-
-        let metadata = Self::populate_metadata(&alignments);
+        Self::from_reader(
+            alignment_strings.as_bytes(),
+            "",
+            false,
+            false,
+            |_, _| Ok(()),
+        )
+        .unwrap()
+    }
 
-        PAF {
-            alignments,
-            metadata,
-        }
+    fn add_alignment(&mut self, alignment: &PafAlignment, offset: u64, line_number: usize) {
+        self.metadata.insert(
+            alignment.query.clone(),
+            PafMetadata {
+                length: alignment.query_length,
+            },
+        );
+        self.metadata.insert(
+            alignment.target.clone(),
+            PafMetadata {
+                length: alignment.target_length,
+            },
+        );
+        self.pairs.insert(AlignmentPair {
+            target_name: alignment.target.clone(),
+            query_name: alignment.query.clone(),
+            strand: alignment.strand,
+        });
+        self.offsets.push(offset);
+        self.line_numbers.push(line_number);
     }
 
-    fn populate_metadata(alignments: &Vec<PafAlignment>) -> PafLookup {
-        let mut lookup: PafLookup = HashMap::new();
-        for alignment in alignments {
-            lookup.insert(
-                alignment.query.clone(),
-                PafMetadata {
-                    length: alignment.query_length,
-                },
-            );
-            lookup.insert(
-                alignment.target.clone(),
-                PafMetadata {
-                    length: alignment.target_length,
-                },
-            );
-        }
+    pub fn get_metadata(&self) -> &PafLookup {
+        &self.metadata
+    }
 
-        lookup
+    pub fn get_offsets(&self) -> &Vec<u64> {
+        &self.offsets
     }
 
-    pub fn get_alignments(&self) -> &Vec<PafAlignment> {
-        &self.alignments
+    // The line of each record in the file, records skipped as invalid have none
+    pub fn get_line_numbers(&self) -> &[usize] {
+        &self.line_numbers
     }
 
-    pub fn get_metadata(&self) -> &PafLookup {
-        &self.metadata
+    // The file the input was spooled to if it was
+    pub fn get_spooled(&self) -> Option<&str> {
+        self.spool.as_ref().map(|spool| spool.path())
     }
 
     // returns pairs (without duplicates) of the alignments
-    // what was the query and the target()
+    // what was the query and the target() and in which orientation
     // this helps speed up alignment
-    pub fn get_unique_alignments(&self) -> HashSet<AlignmentPair> {
-        self.alignments
-            .iter()
-            .map(|aln: &PafAlignment| AlignmentPair {
-                target_name: aln.target.clone(),
-                query_name: aln.query.clone(),
-            })
-            .collect::<HashSet<AlignmentPair>>()
+    pub fn get_unique_alignments(&self) -> &HashSet<AlignmentPair> {
+        &self.pairs
     }
 }
 
@@ -253,7 +560,7 @@ mod tests {
 
     #[test]
     fn test_parse_alignment() {
-        let aln = PafAlignment::from_str(TEST_PAF_STRING);
+        let aln = PafAlignment::from_str(TEST_PAF_STRING).unwrap();
         let aln2 = PafAlignment::new(
             "qry",
             330243,
@@ -264,12 +571,101 @@ mod tests {
             330243,
             0,
             330243,
+            330243,
+            330243,
+            60,
             "330243M",
         );
+        let tags: Vec<(&str, Tag)> = vec![
+            ("NM", Tag::Int(0)),
+            ("ms", Tag::Int(660486)),
+            ("AS", Tag::Int(660486)),
+            ("nn", Tag::Int(0)),
+            ("tp", Tag::Char('P')),
+            ("cm", Tag::Int(62290)),
+            ("s1", Tag::Int(329202)),
+            ("s2", Tag::Int(262341)),
+            ("de", Tag::Float(0.0)),
+            ("rl", Tag::Int(2730)),
+        ];
+        let mut aln2 = aln2;
+        aln2.tags = tags
+            .into_iter()
+            .map(|(k, v)| (String::from(k), v))
+            .collect();
 
         assert_eq!(aln, aln2);
     }
 
+    #[test]
+    fn test_parse_tags() {
+        let aln = PafAlignment::from_str(TEST_PAF_STRING).unwrap();
+
+        assert_eq!(aln.tag_int("NM"), Some(0));
+        assert_eq!(aln.tag_char("tp"), Some('P'));
+        assert_eq!(aln.tag_float("de"), Some(0.0));
+        // wrong type or missing
+        assert_eq!(aln.tag_float("NM"), None);
+        assert_eq!(aln.tag_float("dv"), None);
+        // cg is not kept as a tag
+        assert!(!aln.tags.contains_key("cg"));
+
+        assert_eq!(Tag::from_str("Z:x:y"), Ok(Tag::String(String::from("x:y"))));
+
+        let empty = format!("{}\txx:Z:", TEST_PAF_STRING);
+        let aln = PafAlignment::from_str(&empty[..]).unwrap();
+        assert_eq!(aln.tags.get("xx"), Some(&Tag::String(String::new())));
+        let short = format!("{}\tNM:i", TEST_PAF_STRING);
+        assert!(PafAlignment::from_str(&short[..]).is_err());
+        assert_eq!(format!("{}", Tag::Float(0.25)), "f:0.25");
+        // hex and array values are written back with their own type
+        for tag in &["H:1AE301", "B:c,1,-2,3"] {
+            assert_eq!(format!("{}", Tag::from_str(tag).unwrap()), *tag);
+        }
+
+        let invalid = TEST_PAF_STRING.replace("de:f:0", "de:f:zero");
+        assert_eq!(
+            PafAlignment::from_str(&invalid[..]),
+            Err(FieldError::new("de", "could not parse f:zero"))
+        );
+        let invalid = TEST_PAF_STRING.replace("tp:A:P", "tp");
+        assert!(PafAlignment::from_str(&invalid[..]).is_err());
+    }
+
+    #[test]
+    fn test_cs_to_cigar() {
+        // short form
+        assert_eq!(
+            cs_to_cigar(":6-ata:10+gtc:4*at:3"),
+            Ok(String::from("6=3D10=3I4=1X3="))
+        );
+        // long form
+        assert_eq!(
+            cs_to_cigar("=ACGTN*at*gc=AC-tt"),
+            Ok(String::from("5=2X2=2D"))
+        );
+        // introns
+        assert_eq!(cs_to_cigar(":5~gt100ag:5"), Ok(String::from("5=100D5=")));
+        // not a cs string
+        assert!(cs_to_cigar("10M").is_err());
+    }
+
+    #[test]
+    fn test_parse_alignment_cs() {
+        let cs_line = TEST_PAF_STRING.replace("cg:Z:330243M", "cs:Z::330243");
+        let aln = PafAlignment::from_str(&cs_line[..]).unwrap();
+
+        assert_eq!(aln.cigar, Some(String::from("330243=")));
+    }
+
+    #[test]
+    fn test_parse_alignment_mapping_only() {
+        let mapping_line = TEST_PAF_STRING.replace("\tcg:Z:330243M", "");
+        let aln = PafAlignment::from_str(&mapping_line[..]).unwrap();
+
+        assert_eq!(aln.cigar, None);
+    }
+
     #[test]
     fn test_paf_metadata() {
         let paf = PAF::from_str(TEST_PAF_STRING);
@@ -282,6 +678,79 @@ mod tests {
         assert_eq!(metadata.len(), lookup.len());
     }
 
+    #[test]
+    fn test_paf_offsets() {
+        let paf_string = format!("{}\n\n{}\n", TEST_PAF_STRING, TEST_PAF_STRING);
+        let mut records: Vec<usize> = Vec::new();
+        let paf = PAF::from_reader(paf_string.as_bytes(), "", true, false, |record, _| {
+            records.push(record);
+            Ok(())
+        })
+        .unwrap();
+
+        // the empty line is not a record
+        assert_eq!(records, vec![0, 1]);
+        assert_eq!(
+            paf.get_offsets(),
+            &vec![0, TEST_PAF_STRING.len() as u64 + 2]
+        );
+        let spooled = std::fs::read_to_string(paf.get_spooled().unwrap()).unwrap();
+        assert_eq!(spooled, paf_string);
+    }
+
+    #[test]
+    fn test_paf_invalid_record() {
+        let invalid = TEST_PAF_STRING.replacen("\t0\t", "\tx\t", 1);
+        let paf_string = format!("{}\n{}\n{}\n", TEST_PAF_STRING, invalid, TEST_PAF_STRING);
+
+        let e = PAF::from_reader(paf_string.as_bytes(), "x.paf", false, false, |_, _| Ok(()))
+            .unwrap_err();
+        assert_eq!(
+            format!("{}", e),
+            "x.paf:2: invalid query start: could not parse x"
+        );
+
+        // skipped records don't get a record number
+        let mut records: Vec<usize> = Vec::new();
+        let paf = PAF::from_reader(paf_string.as_bytes(), "x.paf", false, true, |record, _| {
+            records.push(record);
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(records, vec![0, 1]);
+        assert_eq!(paf.get_offsets().len(), 2);
+        assert_eq!(paf.get_line_numbers(), &[1, 3]);
+    }
+
+    #[test]
+    fn test_paf_invalid_coordinates() {
+        let past_end = "qry\t100\t50\t150\t-\ttgt\t200\t0\t100\t100\t100\t60";
+        let inverted = "qry\t100\t0\t50\t+\ttgt\t200\t90\t40\t50\t50\t60";
+
+        let e = PafAlignment::from_str(past_end).unwrap_err();
+        assert_eq!(
+            (&e.field[..], &e.message[..]),
+            ("query end", "150 is beyond the length 100")
+        );
+        let e = PafAlignment::from_str(inverted).unwrap_err();
+        assert_eq!(
+            (&e.field[..], &e.message[..]),
+            ("target start", "90 is after the end 40")
+        );
+
+        let paf_string = format!("{}\n{}\n{}\n", past_end, TEST_PAF_STRING, inverted);
+        let e = PAF::from_reader(paf_string.as_bytes(), "x.paf", false, false, |_, _| Ok(()))
+            .unwrap_err();
+        assert_eq!(
+            format!("{}", e),
+            "x.paf:1: invalid query end: 150 is beyond the length 100"
+        );
+
+        let paf =
+            PAF::from_reader(paf_string.as_bytes(), "x.paf", false, true, |_, _| Ok(())).unwrap();
+        assert_eq!(paf.get_offsets().len(), 1);
+    }
+
     #[test]
     fn test_paf_unique_alignments() {
         let paf = PAF::from_str(TEST_PAF_STRING);
@@ -290,8 +759,30 @@ mod tests {
         unique_alignments.insert(AlignmentPair {
             target_name: String::from("tgt"),
             query_name: String::from("qry"),
+            strand: types::Strand::Forward,
         });
 
-        assert_eq!(paf.get_unique_alignments(), unique_alignments);
+        assert_eq!(paf.get_unique_alignments(), &unique_alignments);
+    }
+
+    #[test]
+    fn test_paf_unique_alignments_both_strands() {
+        let paf_string = format!(
+            "{}\n{}",
+            TEST_PAF_STRING,
+            TEST_PAF_STRING.replacen("\t+\t", "\t-\t", 1)
+        );
+        let paf = PAF::from_str(&paf_string);
+        let mut unique_alignments: HashSet<AlignmentPair> = HashSet::new();
+
+        for strand in [types::Strand::Forward, types::Strand::Reverse].iter() {
+            unique_alignments.insert(AlignmentPair {
+                target_name: String::from("tgt"),
+                query_name: String::from("qry"),
+                strand: *strand,
+            });
+        }
+
+        assert_eq!(paf.get_unique_alignments(), &unique_alignments);
     }
 }
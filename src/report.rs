@@ -0,0 +1,155 @@
+/*
+A tab separated report of PAF records that need a closer look e.g. records that
+were only approximated or were removed by the prefilter before indexing.
+Records are identified by their line in the PAF counting from 1, so lines that
+are empty or skipped with --skip-invalid don't shift the records after them.
+
+|Col|Type   |Description                               |
+|--:|:-----:|:-----------------------------------------|
+|1  |int    |Line number                               |
+|2  |string |Query sequence name                       |
+|3  |int    |Query start                               |
+|4  |int    |Query end                                 |
+|5  |char   |Relative strand: "+" or "-"               |
+|6  |string |Target sequence name                      |
+|7  |int    |Target start                              |
+|8  |int    |Target end                                |
+|9  |string |Status                                    |
+
+The status is one of approximate, removed_mapping_quality, removed_block_length,
+removed_identity or removed_not_primary.
+*/
+
+use std::fmt;
+use std::fs;
+use std::io::{self, BufWriter, Write};
+
+use crate::error::{Error, Result};
+use crate::filter::types;
+use crate::paf;
+
+#[derive(Debug, PartialEq)]
+pub enum Status {
+    Approximate, // indexed from its coordinates because it has no alignment string
+
+    // removed before indexing by the prefilter
+    LowMappingQuality,
+    ShortBlock,
+    LowIdentity,
+    NotPrimary,
+}
+
+impl Status {
+    pub fn is_removed(&self) -> bool {
+        !matches!(self, Status::Approximate)
+    }
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Status::Approximate => write!(f, "approximate"),
+            Status::LowMappingQuality => write!(f, "removed_mapping_quality"),
+            Status::ShortBlock => write!(f, "removed_block_length"),
+            Status::LowIdentity => write!(f, "removed_identity"),
+            Status::NotPrimary => write!(f, "removed_not_primary"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ReportEntry {
+    pub record: usize,
+    pub query: String,
+    pub query_start: u32,
+    pub query_end: u32,
+    pub strand: types::Strand,
+    pub target: String,
+    pub target_start: u32,
+    pub target_end: u32,
+    pub status: Status,
+}
+
+#[derive(Debug, Default)]
+pub struct Report {
+    entries: Vec<ReportEntry>,
+}
+
+impl Report {
+    pub fn add(&mut self, record: usize, alignment: &paf::PafAlignment, status: Status) {
+        self.entries.push(ReportEntry {
+            record,
+            query: alignment.query.clone(),
+            query_start: alignment.query_start,
+            query_end: alignment.query_end,
+            strand: alignment.strand,
+            target: alignment.target.clone(),
+            target_start: alignment.target_start,
+            target_end: alignment.target_end,
+            status,
+        });
+    }
+
+    pub fn count(&self, status: Status) -> usize {
+        self.entries.iter().filter(|e| e.status == status).count()
+    }
+
+    pub fn count_removed(&self) -> usize {
+        self.entries
+            .iter()
+            .filter(|e| e.status.is_removed())
+            .count()
+    }
+
+    // line_numbers holds the line in the PAF of each record
+    pub fn write<W: Write>(&self, line_numbers: &[usize], writer: &mut W) -> io::Result<()> {
+        for e in &self.entries {
+            writeln!(
+                writer,
+                "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                line_numbers[e.record],
+                e.query,
+                e.query_start,
+                e.query_end,
+                e.strand,
+                e.target,
+                e.target_start,
+                e.target_end,
+                e.status
+            )?;
+        }
+
+        writer.flush()
+    }
+
+    pub fn to_file(&self, line_numbers: &[usize], fp: &str) -> Result<()> {
+        fs::File::create(fp)
+            .and_then(|file| self.write(line_numbers, &mut BufWriter::new(file)))
+            .map_err(|e| Error::io(fp, e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_report() {
+        let alignment =
+            paf::PafAlignment::from_str("qry\t100\t10\t50\t-\ttgt\t200\t20\t60\t0\t40\t255")
+                .unwrap();
+        let mut report = Report::default();
+        report.add(3, &alignment, Status::Approximate);
+
+        // record 3 is on line 6 of the PAF
+        let line_numbers = vec![1, 2, 4, 6];
+        let mut output: Vec<u8> = Vec::new();
+        report.write(&line_numbers, &mut output).unwrap();
+
+        assert_eq!(1, report.count(Status::Approximate));
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "6\tqry\t10\t50\t-\ttgt\t20\t60\tapproximate\n"
+        );
+    }
+}
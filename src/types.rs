@@ -1,4 +1,5 @@
 use chrono::{DateTime, Local};
+use std::str::FromStr;
 
 #[derive(Copy, Clone, Debug)]
 pub struct Penalties {
@@ -8,59 +9,216 @@ pub struct Penalties {
     pub gap_extend: u8,
 }
 
+impl Default for Penalties {
+    fn default() -> Self {
+        Penalties {
+            mismatch: 1,
+            matches: 0,
+            gap_open: 1,
+            gap_extend: 1,
+        }
+    }
+}
+
+// Thresholds a PAF record has to pass to be indexed, the defaults let every
+// record through
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Prefilter {
+    pub min_mapping_quality: u8,
+    pub min_block_length: u32,
+    pub min_identity: f64,  // gap-compressed identity 0..1
+    pub primary_only: bool, // drop records with a tp:A other than P
+}
+
+// How much of a PAF record the global alignment has to run along for the
+// record to be kept. Records the alignment never runs along are never kept.
+#[derive(Copy, Clone, Debug)]
+pub struct MinSupport {
+    pub bases: u32,    // bases of the match runs of the record on the path
+    pub fraction: f64, // of the bases in the match runs of the record
+}
+
+impl Default for MinSupport {
+    fn default() -> Self {
+        MinSupport {
+            bases: 1,
+            fraction: 0.0,
+        }
+    }
+}
+
+// How much consecutive segments overlap, either as a fraction of the segment
+// length or in bases
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Overlap {
+    Fraction(f64),
+    Bases(usize),
+}
+
+impl FromStr for Overlap {
+    type Err = String;
+
+    // a number with a decimal point is a fraction in 0..1, a whole number is bases
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.contains('.') {
+            match s.parse::<f64>() {
+                Ok(f) if (0.0..1.0).contains(&f) => Ok(Overlap::Fraction(f)),
+                _ => Err(String::from(
+                    "expected a fraction of at least 0 and less than 1",
+                )),
+            }
+        } else {
+            s.parse::<usize>()
+                .map(Overlap::Bases)
+                .map_err(|_| String::from("expected a fraction or a whole number of bases"))
+        }
+    }
+}
+
+// What makes a cell of a segment a match when aligning
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum MatchMode {
+    Index,    // the cell lies in a match run of an indexed PAF record
+    Sequence, // the query and target bases of the cell agree
+    Both,     // the bases agree and the cell lies in a match run
+}
+
+impl MatchMode {
+    pub fn uses_index(&self) -> bool {
+        *self != MatchMode::Sequence
+    }
+
+    pub fn uses_sequence(&self) -> bool {
+        *self != MatchMode::Index
+    }
+}
+
+impl FromStr for MatchMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "index" => Ok(MatchMode::Index),
+            "sequence" => Ok(MatchMode::Sequence),
+            "both" => Ok(MatchMode::Both),
+            _ => Err(String::from("expected one of index, sequence or both")),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct AppConfig {
     pub input_paf: String,
 
     pub mashmap_filepath: Option<String>,
+    pub min_mapping_identity: f64, // ignore MashMap mappings below this estimated identity
+
+    pub fasta: Vec<String>, // query and target sequences
+    pub match_mode: MatchMode,
+
+    pub output: Option<String>,           // None for stdout
+    pub alignment_output: Option<String>, // the global alignment of each pair as PAF
+    pub support_output: Option<String>,   // the support of each indexed record
+    pub report: Option<String>,
 
     pub segment_length: usize,
-    pub step: bool, // Use overlapping segments or not? Set to true everywhere for now
+    pub overlap: Overlap, // less than segment_length
 
     pub thread_count: usize,
     pub penalties: Penalties,
+    pub prefilter: Prefilter,
+    pub min_support: MinSupport,
     pub adapt: bool,
+    pub bgzip: bool,        // compress the output with BGZF
+    pub trim: bool,         // clip kept records to the span the global alignment supports
+    pub approximate: bool,  // index records without an alignment string from their coordinates
+    pub skip_invalid: bool, // log and drop invalid records instead of aborting
     pub verbosity_level: u8,
     pub start_time: DateTime<Local>,
 }
 
+// The defaults of the command line options
+impl Default for AppConfig {
+    fn default() -> Self {
+        AppConfig {
+            input_paf: String::new(),
+            mashmap_filepath: None,
+            min_mapping_identity: 0.0,
+            fasta: Vec::new(),
+            match_mode: MatchMode::Index,
+            output: None,
+            alignment_output: None,
+            support_output: None,
+            report: None,
+            segment_length: 10,
+            overlap: Overlap::Fraction(0.5),
+            thread_count: 8,
+            penalties: Penalties::default(),
+            prefilter: Prefilter::default(),
+            min_support: MinSupport::default(),
+            adapt: false,
+            bgzip: false,
+            trim: false,
+            approximate: false,
+            skip_invalid: false,
+            verbosity_level: 0,
+            start_time: Local::now(),
+        }
+    }
+}
+
 impl AppConfig {
-    #[allow(dead_code)] // TODO: exists for testing purposes
-    pub fn new(
-        paf_filepath: &str,
-        mashmap_filepath_: Option<&str>,
-        segment_length: usize,
-        thread_count: usize,
-        penalties: Option<Penalties>,
-        adapt: bool,
-        verbosity_level: u8,
-    ) -> Self {
-        let penalties = match penalties {
-            Some(p) => p,
-            _ => Penalties {
-                // default penalties
-                mismatch: 1,
-                matches: 0,
-                gap_open: 1,
-                gap_extend: 1,
-            },
+    // How far apart consecutive segments start
+    pub fn step_size(&self) -> usize {
+        let step_size = match self.overlap {
+            Overlap::Fraction(f) => (self.segment_length as f64 * (1.0 - f)).floor() as usize,
+            Overlap::Bases(b) => self.segment_length.saturating_sub(b),
         };
 
-        let mashmap_filepath: Option<String> = match mashmap_filepath_ {
-            Some(fp) => Some(String::from(fp)),
-            _ => None,
+        std::cmp::max(step_size, 1)
+    }
+}
+
+// A single threaded configuration for tests
+#[cfg(test)]
+pub fn test_config() -> AppConfig {
+    AppConfig {
+        input_paf: String::from("test.paf"),
+        thread_count: 1,
+        ..Default::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_overlap() {
+        assert_eq!("0.25".parse(), Ok(Overlap::Fraction(0.25)));
+        assert_eq!("3".parse(), Ok(Overlap::Bases(3)));
+        assert!("1.0".parse::<Overlap>().is_err());
+        assert!("-1".parse::<Overlap>().is_err());
+
+        let mut config = AppConfig {
+            overlap: Overlap::Fraction(0.25),
+            ..test_config()
         };
+        assert_eq!(config.step_size(), 7);
+        config.overlap = Overlap::Bases(3);
+        assert_eq!(config.step_size(), 7);
+        config.overlap = Overlap::Bases(10);
+        assert_eq!(config.step_size(), 1);
+    }
 
-        AppConfig {
-            verbosity_level,
-            input_paf: String::from(paf_filepath),
-            mashmap_filepath,
-            segment_length,
-            step: true,
-            thread_count,
-            penalties,
-            adapt,
-            start_time: Local::now(),
-        }
+    #[test]
+    fn test_match_mode() {
+        assert_eq!("index".parse(), Ok(MatchMode::Index));
+        assert_eq!("both".parse(), Ok(MatchMode::Both));
+        assert!("bases".parse::<MatchMode>().is_err());
+
+        let mode = MatchMode::Sequence;
+        assert!(mode.uses_sequence() && !mode.uses_index());
+        assert!(MatchMode::Both.uses_index() && MatchMode::Both.uses_sequence());
     }
 }